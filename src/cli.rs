@@ -0,0 +1,441 @@
+use crate::{
+    config::{self, ConfigLayer},
+    filter::Decision,
+    health::{self, Health, Verdict},
+    service::{self, State},
+    source,
+    store::{self, ConfigStore, FileStore},
+    utils,
+};
+use clap::{Args, Subcommand};
+use serde::Serialize;
+use std::{net::IpAddr, path::Path, time::Duration};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use toml::Value;
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Show or change the stored settings
+    Config {
+        #[clap(subcommand)]
+        action: ConfigAction,
+    },
+    /// Show whether the service runs and how its last poll went. Exits with 0
+    /// if healthy, 2 if failing, 3 if not running and 4 if not installed
+    Status {
+        /// Print JSON instead of text
+        #[clap(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Start the service and wait until it runs
+    Start(Wait),
+    /// Stop the service and wait until it has stopped
+    Stop(Wait),
+    /// Stop the service, then start it, waiting for each
+    Restart(Wait),
+    /// List every adapter address and whether the filters keep it, with the
+    /// check or rule that decided; changes nothing
+    Show {
+        /// Print JSON instead of a table
+        #[clap(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Tell the running service to act now: poll, reload, shutdown,
+    /// rotate-logs or diagnostics
+    Control {
+        #[clap(value_enum)]
+        action: service::Control,
+    },
+    /// Run the polling loop in the console until Ctrl+C, without the service
+    /// manager
+    Run {
+        /// Poll once, write the IP file and exit
+        #[clap(long, default_value_t = false)]
+        once: bool,
+        /// Print the IP file instead of writing it
+        #[clap(long, default_value_t = false, requires = "once")]
+        print: bool,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct Wait {
+    /// Seconds to wait for the service to get there
+    #[clap(long = "timeout", default_value_t = service::DEFAULT_TIMEOUT_SECS)]
+    timeout: u64,
+}
+
+impl Wait {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigAction {
+    /// Print every setting in effect and where its value comes from
+    List,
+    /// Print the value in effect for one setting
+    Get { key: String },
+    /// Store one setting; lists can be separated with "," (ipv6) or ";" (filters)
+    Set { key: String, value: String },
+    /// Remove one stored setting, so the config file or the default applies
+    Unset { key: String },
+    /// Write the stored settings as TOML to FILE, or print them
+    Export { file: Option<String> },
+    /// Replace the stored settings with those in a TOML file
+    Import { file: String },
+}
+
+pub fn run(
+    service_name: &str,
+    command: &Command,
+    cli: &ConfigLayer,
+    config_path: Option<&str>,
+) -> service::Result<()> {
+    match command {
+        Command::Config { action } => config(service_name, action, cli, config_path),
+        Command::Status { json } => status(service_name, *json, cli, config_path),
+        Command::Start(wait) => {
+            console_logging();
+            service::start_service(service_name, wait.timeout())
+        }
+        Command::Stop(wait) => {
+            console_logging();
+            service::stop_service(service_name, wait.timeout())
+        }
+        Command::Restart(wait) => {
+            console_logging();
+            service::restart_service(service_name, wait.timeout())
+        }
+        Command::Control { action } => {
+            console_logging();
+            service::send_control(service_name, *action)
+        }
+        Command::Show { json } => show(service_name, *json, cli, config_path),
+        Command::Run { once, print } => run_console(service_name, *once, *print, cli, config_path),
+    }
+}
+
+/// Runs every address the source reports through the filter in effect.
+fn show(
+    service_name: &str,
+    json: bool,
+    cli: &ConfigLayer,
+    config_path: Option<&str>,
+) -> service::Result<()> {
+    #[derive(Serialize)]
+    struct Row {
+        adapter: String,
+        kind: &'static str,
+        address: IpAddr,
+        kept: bool,
+        reason: &'static str,
+        rule: Option<String>,
+        detail: String,
+    }
+
+    let config = config::load(service_name, cli, config_path)?;
+    let filter = config.address_filter();
+    let adapters = source::system_source().adapters().map_err(|e| {
+        eprintln!("Error reading network adapters: {}", e);
+        service::other_error(e)
+    })?;
+
+    let mut rows = vec![];
+    for adapter in &adapters {
+        let mut addresses = adapter.addresses.clone();
+        addresses.sort();
+        addresses.dedup();
+        for address in addresses {
+            let decision = filter.decide(adapter, &address);
+            rows.push(Row {
+                adapter: adapter.name.clone(),
+                kind: adapter.kind.as_str(),
+                address,
+                kept: decision.keep(),
+                reason: decision.reason(),
+                rule: match decision {
+                    Decision::Rule(_, rule) => Some(rule.to_string()),
+                    _ => None,
+                },
+                detail: decision.to_string(),
+            });
+        }
+    }
+
+    if json {
+        let json = serde_json::to_string_pretty(&rows).map_err(service::other_error)?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    // At least as wide as the seven letter headers.
+    let width = |column: &dyn Fn(&Row) -> usize| rows.iter().map(column).chain([7]).max();
+    let adapter_width = width(&|r| r.adapter.len()).unwrap_or_default();
+    let address_width = width(&|r| r.address.to_string().len()).unwrap_or_default();
+    println!(
+        "{:adapter_width$}  {:address_width$}  {:7}  REASON",
+        "ADAPTER", "ADDRESS", "VERDICT"
+    );
+    for row in &rows {
+        println!(
+            "{:adapter_width$}  {:address_width$}  {:7}  {}",
+            row.adapter,
+            row.address.to_string(),
+            if row.kept { "kept" } else { "dropped" },
+            row.detail
+        );
+    }
+    Ok(())
+}
+
+fn run_console(
+    service_name: &str,
+    once: bool,
+    print: bool,
+    cli: &ConfigLayer,
+    config_path: Option<&str>,
+) -> service::Result<()> {
+    let config = config::load(service_name, cli, config_path)
+        .map_err(|e| service::exit_error(service::EXIT_INVALID_CONFIG, e))?;
+    // Log messages would end up mixed into the printed file.
+    if !print {
+        if let Err(e) = utils::logging(Some(&config.log)) {
+            eprintln!("Error logging: {}", e);
+        }
+    }
+
+    if !once {
+        return service::run_console(service_name, cli, config_path);
+    }
+    match service::run_once(&config, print) {
+        Ok(Some(content)) => {
+            print!("{}", content);
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(e) => {
+            eprintln!("Error polling: {}", e);
+            Err(service::other_error(e))
+        }
+    }
+}
+
+/// The service functions report progress and errors through `tracing`.
+fn console_logging() {
+    if let Err(e) = utils::logging(None) {
+        eprintln!("Error logging: {}", e);
+    }
+}
+
+/// Combines the service manager state with the health record, prints both
+/// and exits with the verdict's code.
+fn status(
+    service_name: &str,
+    json: bool,
+    cli: &ConfigLayer,
+    config_path: Option<&str>,
+) -> service::Result<()> {
+    #[derive(Serialize)]
+    struct Status<'a> {
+        service: State,
+        pid: Option<u32>,
+        verdict: Verdict,
+        health: Option<&'a Health>,
+    }
+
+    let (state, pid) = match service::state(service_name) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("Error querying {}: {}", service_name, e);
+            return Err(e);
+        }
+    };
+    let config = config::load(service_name, cli, config_path)?;
+    let health = config
+        .health
+        .as_ref()
+        .and_then(|path| Health::load(Path::new(path)).ok());
+    let verdict = health::check(
+        state,
+        pid,
+        health.as_ref(),
+        config.poll_rate(),
+        OffsetDateTime::now_utc(),
+    );
+
+    if json {
+        let status = Status {
+            service: state,
+            pid,
+            verdict,
+            health: health.as_ref(),
+        };
+        let json = serde_json::to_string_pretty(&status).map_err(service::other_error)?;
+        println!("{}", json);
+    } else {
+        match pid {
+            Some(pid) => println!("Service:      {} (pid {})", state, pid),
+            None => println!("Service:      {}", state),
+        }
+        println!("Health:       {}", verdict);
+        if let Some(health) = &health {
+            println!("Started:      {}", timestamp(Some(health.started)));
+            println!("Last success: {}", timestamp(health.last_success));
+            println!("Last write:   {}", timestamp(health.last_write));
+            match &health.last_error {
+                Some(error) => println!(
+                    "Last error:   {} {}",
+                    timestamp(Some(error.timestamp)),
+                    error.message
+                ),
+                None => println!("Last error:   none"),
+            }
+            println!("Addresses:    {}", health.addresses);
+            println!(
+                "Failures:     {} of {} polls, {} in a row",
+                health.failures, health.polls, health.consecutive_failures
+            );
+        }
+    }
+
+    std::process::exit(verdict.exit_code());
+}
+
+fn timestamp(time: Option<OffsetDateTime>) -> String {
+    time.and_then(|t| t.format(&Rfc3339).ok())
+        .unwrap_or_else(|| "never".to_owned())
+}
+
+/// Prints the settings in effect as TOML, each with its source as a comment.
+pub fn show_config(
+    service_name: &str,
+    cli: &ConfigLayer,
+    config_path: Option<&str>,
+) -> service::Result<()> {
+    for setting in config::effective(service_name, cli, config_path)? {
+        println!("{} = {}  # {}", setting.key, setting.value, setting.source);
+    }
+    Ok(())
+}
+
+fn config(
+    service_name: &str,
+    action: &ConfigAction,
+    cli: &ConfigLayer,
+    config_path: Option<&str>,
+) -> service::Result<()> {
+    let mut store = store::system_store(service_name);
+
+    match action {
+        ConfigAction::List => show_config(service_name, cli, config_path),
+        ConfigAction::Get { key } => {
+            check_key(key)?;
+            let settings = config::effective(service_name, cli, config_path)?;
+            if let Some(setting) = settings.iter().find(|s| s.key == key) {
+                match &setting.value {
+                    Value::String(s) => println!("{}", s),
+                    value => println!("{}", value),
+                }
+            }
+            Ok(())
+        }
+        ConfigAction::Set { key, value } => {
+            check_key(key)?;
+            let result = if key == config::CONFIG.name {
+                if value.is_empty() {
+                    store.unset(key)
+                } else {
+                    config::CONFIG.set(store.as_mut(), value)
+                }
+            } else {
+                let layer = config::parse(key, value).map_err(|e| {
+                    eprintln!("Invalid value for {}: {}", key, e);
+                    service::other_error(e)
+                })?;
+                layer.save(store.as_mut()).map_err(|(_, e)| e)
+            };
+            result.map_err(|e| {
+                eprintln!("Error setting {}: {}", key, e);
+                service::other_error(e)
+            })
+        }
+        ConfigAction::Unset { key } => {
+            check_key(key)?;
+            store.unset(key).map_err(|e| {
+                eprintln!("Error removing {}: {}", key, e);
+                service::other_error(e)
+            })
+        }
+        ConfigAction::Export { file } => {
+            let entries = store.entries().map_err(|e| {
+                eprintln!("Error reading {}: {}", store.name(), e);
+                service::other_error(e)
+            })?;
+            let table: toml::Table = entries.into_iter().collect();
+            let content = toml::to_string(&table).map_err(service::other_error)?;
+            match file {
+                Some(path) => std::fs::write(path, content).map_err(|e| {
+                    eprintln!("Error writing {}: {}", path, e);
+                    service::other_error(e)
+                }),
+                None => {
+                    print!("{}", content);
+                    Ok(())
+                }
+            }
+        }
+        ConfigAction::Import { file } => import(store.as_mut(), file),
+    }
+}
+
+/// Validates the whole file before touching the store, then stores every
+/// setting in it and only then removes the ones it leaves out, so a store
+/// that fails part way keeps its settings.
+fn import(store: &mut dyn ConfigStore, path: &str) -> service::Result<()> {
+    let file = FileStore::new(path);
+    let layer = ConfigLayer::from_file(path.as_ref()).map_err(|e| {
+        eprintln!("Invalid settings: {}", e);
+        service::other_error(e)
+    })?;
+    let config_path = config::CONFIG
+        .get(&file)
+        .map_err(service::other_error)?
+        .filter(|p| !p.is_empty());
+
+    let set: Vec<&str> = layer.entries().into_iter().map(|(key, _)| key).collect();
+    let result = layer
+        .save(store)
+        .and_then(|()| {
+            config::KEYS
+                .iter()
+                .filter(|key| !set.contains(key))
+                .try_for_each(|key| store.unset(key).map_err(|e| (*key, e)))
+        })
+        .and_then(|()| match &config_path {
+            Some(p) => config::CONFIG
+                .set(store, p)
+                .map_err(|e| (config::CONFIG.name, e)),
+            None => store
+                .unset(config::CONFIG.name)
+                .map_err(|e| (config::CONFIG.name, e)),
+        });
+    result.map_err(|(key, e)| {
+        eprintln!("Error setting {}: {}", key, e);
+        service::other_error(e)
+    })
+}
+
+fn check_key(key: &str) -> service::Result<()> {
+    if key == config::CONFIG.name || config::KEYS.contains(&key) {
+        return Ok(());
+    }
+    eprintln!(
+        "Unknown setting {}, expected one of: {}, {}",
+        key,
+        config::KEYS.join(", "),
+        config::CONFIG.name
+    );
+    Err(service::other_error(format!("unknown setting {}", key)))
+}
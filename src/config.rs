@@ -0,0 +1,1076 @@
+use crate::{
+    filter::{self, AddressFilter, FilterRule, Ipv6Scope},
+    health,
+    history::HistoryPolicy,
+    journal,
+    output::OutputFormat,
+    service::Result,
+    store::{self, ConfigStore, FileStore},
+    writer::RetryPolicy,
+};
+use std::{
+    fmt,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use toml::Value;
+
+/// Environment variable naming the TOML config file.
+pub const CONFIG_ENV: &str = "IP_TO_FILE_CONFIG";
+/// Prefix of the environment variables that override single settings, e.g.
+/// `IP_TO_FILE_TIME_DELAY=60`.
+pub const ENV_PREFIX: &str = "IP_TO_FILE_";
+
+pub const DEFAULT_POLL_RATE: u64 = 15 * 60;
+/// Quiet period after an address change notification before polling.
+pub const DEFAULT_DEBOUNCE_MS: u64 = 2000;
+pub const DEFAULT_JOURNAL_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Longest journal rotation age accepted, 100 years.
+pub const MAX_JOURNAL_MAX_AGE: u64 = 100 * 365 * 24 * 60 * 60;
+/// First retry delay after a failed poll, doubled for every further failure.
+pub const DEFAULT_BACKOFF_MS: u64 = 5000;
+
+/// A setting that could not be read, and where it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    /// Store, config file path or environment variable.
+    pub source: String,
+    /// Offending setting, if the error is about a single value.
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "{}: {}: {}", self.source, key, self.message),
+            None => write!(f, "{}: {}", self.source, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A type a setting can hold, and how it maps to a stored value. Values are
+/// parsed leniently: the environment only has strings and older versions
+/// stored lists as delimited strings and booleans as numbers.
+pub trait Setting: Sized {
+    fn from_value(value: &Value) -> std::result::Result<Self, String>;
+    fn to_value(&self) -> Value;
+
+    /// Rejects a value of the right type that can't be stored.
+    fn check(&self) -> std::result::Result<(), String> {
+        Ok(())
+    }
+}
+
+impl Setting for String {
+    fn from_value(value: &Value) -> std::result::Result<Self, String> {
+        string(value)
+    }
+
+    fn to_value(&self) -> Value {
+        Value::String(self.clone())
+    }
+}
+
+impl Setting for u64 {
+    fn from_value(value: &Value) -> std::result::Result<Self, String> {
+        let n: u64 = number(value)?;
+        n.check()?;
+        Ok(n)
+    }
+
+    /// Values `check` rejects are stored as the largest TOML integer.
+    fn to_value(&self) -> Value {
+        Value::Integer(i64::try_from(*self).unwrap_or(i64::MAX))
+    }
+
+    /// TOML and the settings stores hold signed 64 bit integers.
+    fn check(&self) -> std::result::Result<(), String> {
+        match i64::try_from(*self) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("{} is out of range, at most {}", self, i64::MAX)),
+        }
+    }
+}
+
+impl Setting for u32 {
+    fn from_value(value: &Value) -> std::result::Result<Self, String> {
+        number(value)
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Integer(i64::from(*self))
+    }
+}
+
+impl Setting for bool {
+    fn from_value(value: &Value) -> std::result::Result<Self, String> {
+        match value {
+            Value::Boolean(b) => Ok(*b),
+            Value::Integer(0) => Ok(false),
+            Value::Integer(1) => Ok(true),
+            Value::String(s) => match s.trim().to_ascii_lowercase().as_str() {
+                "true" | "1" => Ok(true),
+                "false" | "0" => Ok(false),
+                _ => Err(format!("expected true or false, got {:?}", s)),
+            },
+            other => Err(format!("expected true or false, got {}", other)),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Boolean(*self)
+    }
+}
+
+impl Setting for OutputFormat {
+    fn from_value(value: &Value) -> std::result::Result<Self, String> {
+        string(value)?.parse()
+    }
+
+    fn to_value(&self) -> Value {
+        Value::String(self.as_str().to_owned())
+    }
+}
+
+impl Setting for Vec<Ipv6Scope> {
+    fn from_value(value: &Value) -> std::result::Result<Self, String> {
+        filter::parse_ipv6_scopes(&list(value, ',')?.join(","))
+    }
+
+    fn to_value(&self) -> Value {
+        Value::String(filter::format_ipv6_scopes(self))
+    }
+}
+
+impl Setting for Vec<FilterRule> {
+    fn from_value(value: &Value) -> std::result::Result<Self, String> {
+        list(value, ';')?
+            .iter()
+            .filter(|rule| !rule.trim().is_empty())
+            .map(|rule| rule.parse())
+            .collect()
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Array(
+            self.iter()
+                .map(|rule| Value::String(rule.to_string()))
+                .collect(),
+        )
+    }
+}
+
+/// An array of tables, or the same array as TOML text, which is how the
+/// environment and the registry hold it.
+impl Setting for Vec<Output> {
+    fn from_value(value: &Value) -> std::result::Result<Self, String> {
+        match value {
+            Value::Array(items) => items.iter().map(Output::from_value).collect(),
+            Value::String(s) if s.trim().is_empty() => Ok(vec![]),
+            Value::String(s) => {
+                let table: toml::Table = format!("outputs = {}", s)
+                    .parse()
+                    .map_err(|e: toml::de::Error| e.message().to_owned())?;
+                Self::from_value(&table["outputs"])
+            }
+            other => Err(format!(
+                "expected a list of tables, got {}",
+                other.type_str()
+            )),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Array(self.iter().map(Output::to_value).collect())
+    }
+}
+
+fn string(value: &Value) -> std::result::Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        other => Err(format!("expected a string, got {}", other.type_str())),
+    }
+}
+
+fn number<T: TryFrom<i64> + FromStr>(value: &Value) -> std::result::Result<T, String> {
+    match value {
+        Value::Integer(i) => T::try_from(*i).map_err(|_| format!("{} is out of range", i)),
+        Value::String(s) => s
+            .trim()
+            .parse()
+            .map_err(|_| format!("expected a number, got {:?}", s)),
+        other => Err(format!("expected a number, got {}", other.type_str())),
+    }
+}
+
+/// An array of strings, or one string split at `separator`.
+fn list(value: &Value, separator: char) -> std::result::Result<Vec<String>, String> {
+    match value {
+        Value::Array(items) => items.iter().map(string).collect(),
+        Value::String(s) => Ok(s.split(separator).map(str::to_owned).collect()),
+        other => Err(format!("expected a list, got {}", other.type_str())),
+    }
+}
+
+/// A setting of type `T` in a `ConfigStore`.
+pub struct Key<T> {
+    pub name: &'static str,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T: Setting> Key<T> {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            marker: PhantomData,
+        }
+    }
+
+    /// A stored value of the wrong type is an `InvalidData` error naming the key.
+    pub fn get(&self, store: &dyn ConfigStore) -> std::io::Result<Option<T>> {
+        match store.get(self.name)? {
+            Some(value) => T::from_value(&value).map(Some).map_err(|message| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}: {}: {}", store.name(), self.name, message),
+                )
+            }),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&self, store: &mut dyn ConfigStore, value: &T) -> std::io::Result<()> {
+        store.set(self.name, value.to_value())
+    }
+}
+
+/// Declares the typed key constants, `KEYS` and the `ConfigLayer` fields from
+/// one list, so a new setting can't be forgotten in one of them.
+macro_rules! settings {
+    ($($key:ident $field:ident: $ty:ty,)*) => {
+        $(pub const $key: Key<$ty> = Key::new(stringify!($field));)*
+
+        /// Names accepted in the config file and, upper cased, in the environment.
+        pub const KEYS: &[&str] = &[$($key.name),*];
+
+        /// Settings from one source; `None` falls through to the next source.
+        #[derive(Clone, Debug, Default, PartialEq)]
+        pub struct ConfigLayer {
+            $(pub $field: Option<$ty>,)*
+        }
+
+        impl ConfigLayer {
+            /// Parses one setting.
+            pub fn set(&mut self, key: &str, value: &Value) -> std::result::Result<(), String> {
+                match key {
+                    $(name if name == $key.name => self.$field = Some(<$ty>::from_value(value)?),)*
+                    _ => return Err("unknown setting".to_owned()),
+                }
+                Ok(())
+            }
+
+            /// The settings this layer has, as stored values.
+            pub fn entries(&self) -> Vec<(&'static str, Value)> {
+                let mut entries = vec![];
+                $(
+                    if let Some(value) = &self.$field {
+                        entries.push(($key.name, value.to_value()));
+                    }
+                )*
+                entries
+            }
+
+            /// The first setting whose value can't be stored.
+            fn check_values(&self) -> std::result::Result<(), (&'static str, String)> {
+                $(
+                    if let Some(value) = &self.$field {
+                        value.check().map_err(|message| ($key.name, message))?;
+                    }
+                )*
+                Ok(())
+            }
+
+            /// Fills the settings this layer leaves unset from `lower`.
+            pub fn or(self, lower: ConfigLayer) -> ConfigLayer {
+                ConfigLayer {
+                    $($field: self.$field.or(lower.$field),)*
+                }
+            }
+        }
+    };
+}
+
+settings! {
+    LOG log: String,
+    IP_LOG ip_log: String,
+    IP_LOG_FORMAT ip_log_format: OutputFormat,
+    TIME_DELAY time_delay: u64,
+    IPV6 ipv6: Vec<Ipv6Scope>,
+    FILTERS filters: Vec<FilterRule>,
+    HEARTBEAT heartbeat: bool,
+    WATCH watch: bool,
+    DEBOUNCE_MS debounce_ms: u64,
+    WRITE_RETRIES write_retries: u32,
+    RETRY_DELAY_MS retry_delay_ms: u64,
+    HISTORY_LEN history_len: u32,
+    HISTORY_MAX_AGE history_max_age: u64,
+    JOURNAL journal: String,
+    JOURNAL_MAX_SIZE journal_max_size: u64,
+    JOURNAL_MAX_AGE journal_max_age: u64,
+    HEALTH health: String,
+    OUTPUTS outputs: Vec<Output>,
+    MAX_FAILURES max_failures: u32,
+    BACKOFF_MS backoff_ms: u64,
+    HTTP http: String,
+}
+
+/// A further IP file written next to `ip_log`, e.g. a JSON file on a share
+/// besides a local text file. Settings it leaves out are the top level ones.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Output {
+    pub path: String,
+    pub format: Option<OutputFormat>,
+    pub ipv6: Option<Vec<Ipv6Scope>>,
+    pub filters: Option<Vec<FilterRule>>,
+    pub heartbeat: Option<bool>,
+    pub write_retries: Option<u32>,
+    pub retry_delay_ms: Option<u64>,
+}
+
+impl Output {
+    fn from_value(value: &Value) -> std::result::Result<Self, String> {
+        let Value::Table(table) = value else {
+            return Err(format!("expected a table, got {}", value.type_str()));
+        };
+        let mut output = Output::default();
+        for (key, value) in table {
+            let error = |message: String| format!("{}: {}", key, message);
+            match key.as_str() {
+                "path" => output.path = String::from_value(value).map_err(error)?,
+                "format" => output.format = Some(Setting::from_value(value).map_err(error)?),
+                "ipv6" => output.ipv6 = Some(Setting::from_value(value).map_err(error)?),
+                "filters" => output.filters = Some(Setting::from_value(value).map_err(error)?),
+                "heartbeat" => output.heartbeat = Some(Setting::from_value(value).map_err(error)?),
+                "write_retries" => {
+                    output.write_retries = Some(Setting::from_value(value).map_err(error)?)
+                }
+                "retry_delay_ms" => {
+                    output.retry_delay_ms = Some(Setting::from_value(value).map_err(error)?)
+                }
+                _ => return Err(error("unknown output setting".to_owned())),
+            }
+        }
+        if output.path.is_empty() {
+            return Err("every output needs a path".to_owned());
+        }
+        Ok(output)
+    }
+
+    fn to_value(&self) -> Value {
+        let mut table = toml::Table::new();
+        table.insert("path".to_owned(), self.path.to_value());
+        let mut insert = |key: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                table.insert(key.to_owned(), value);
+            }
+        };
+        insert("format", self.format.as_ref().map(Setting::to_value));
+        insert("ipv6", self.ipv6.as_ref().map(Setting::to_value));
+        insert("filters", self.filters.as_ref().map(Setting::to_value));
+        insert("heartbeat", self.heartbeat.as_ref().map(Setting::to_value));
+        insert(
+            "write_retries",
+            self.write_retries.as_ref().map(Setting::to_value),
+        );
+        insert(
+            "retry_delay_ms",
+            self.retry_delay_ms.as_ref().map(Setting::to_value),
+        );
+        Value::Table(table)
+    }
+
+    pub fn format(&self, config: &Config) -> OutputFormat {
+        self.format.unwrap_or(config.ip_log_format)
+    }
+
+    pub fn heartbeat(&self, config: &Config) -> bool {
+        self.heartbeat.unwrap_or(config.heartbeat)
+    }
+
+    pub fn address_filter(&self, config: &Config) -> AddressFilter {
+        AddressFilter {
+            ipv6: self.ipv6.clone().unwrap_or_else(|| config.ipv6.clone()),
+            rules: self
+                .filters
+                .clone()
+                .unwrap_or_else(|| config.filters.clone()),
+        }
+    }
+
+    pub fn retry_policy(&self, config: &Config) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.write_retries.unwrap_or(config.write_retries),
+            delay: Duration::from_millis(self.retry_delay_ms.unwrap_or(config.retry_delay_ms)),
+        }
+    }
+}
+
+/// One output as an inline TOML table, e.g. `{ path = "ip.json", format = "json" }`.
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let table: toml::Table = format!("output = {}", s)
+            .parse()
+            .map_err(|e: toml::de::Error| e.message().to_owned())?;
+        Output::from_value(&table["output"])
+    }
+}
+
+/// Path of the TOML config file. Only read from the system store.
+pub const CONFIG: Key<String> = Key::new("config");
+
+impl ConfigLayer {
+    /// Reads and validates every setting in `store`. Unknown keys are errors.
+    pub fn from_store(store: &dyn ConfigStore) -> std::result::Result<Self, ConfigError> {
+        let error = |key: Option<&str>, message: String| ConfigError {
+            source: store.name(),
+            key: key.map(str::to_owned),
+            message,
+        };
+        let entries = store.entries().map_err(|e| error(None, e.to_string()))?;
+
+        let mut layer = ConfigLayer::default();
+        for (key, value) in &entries {
+            if key == CONFIG.name {
+                continue;
+            }
+            layer
+                .set(key, value)
+                .map_err(|message| error(Some(key), message))?;
+        }
+        layer
+            .check()
+            .map_err(|(key, message)| error(Some(key), message))?;
+        Ok(layer)
+    }
+
+    /// Unlike the system store, a config file that was asked for must exist.
+    pub fn from_file(path: &Path) -> std::result::Result<Self, ConfigError> {
+        if let Err(e) = std::fs::metadata(path) {
+            return Err(ConfigError {
+                source: path.display().to_string(),
+                key: None,
+                message: e.to_string(),
+            });
+        }
+        Self::from_store(&FileStore::new(path))
+    }
+
+    /// Reads the `IP_TO_FILE_<KEY>` variables. Unknown names are ignored with
+    /// a warning, since the environment is shared with everything else.
+    pub fn from_env(
+        vars: impl Iterator<Item = (String, String)>,
+    ) -> std::result::Result<Self, ConfigError> {
+        let mut layer = ConfigLayer::default();
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if name == CONFIG_ENV {
+                continue;
+            }
+            let key = key.to_ascii_lowercase();
+            if !KEYS.contains(&key.as_str()) {
+                tracing::warn!("Ignoring unknown setting {}", name);
+                continue;
+            }
+            let error = |message| ConfigError {
+                source: "environment".to_owned(),
+                key: Some(name.clone()),
+                message,
+            };
+            layer
+                .set(&key, &Value::String(value.clone()))
+                .map_err(error)?;
+            if let Err((_, message)) = layer.check() {
+                return Err(error(message));
+            }
+        }
+        Ok(layer)
+    }
+
+    /// Range checks that a value of the right type can still fail.
+    fn check(&self) -> std::result::Result<(), (&'static str, String)> {
+        self.check_values()?;
+        if self.ip_log.as_ref().is_some_and(String::is_empty) {
+            return Err((IP_LOG.name, "must not be empty".to_owned()));
+        }
+        if self.time_delay == Some(0) {
+            return Err((TIME_DELAY.name, "must be at least 1 second".to_owned()));
+        }
+        if self.history_len == Some(0) {
+            return Err((HISTORY_LEN.name, "must be at least 1".to_owned()));
+        }
+        if self
+            .journal_max_age
+            .is_some_and(|age| age > MAX_JOURNAL_MAX_AGE)
+        {
+            return Err((
+                JOURNAL_MAX_AGE.name,
+                format!("must be at most {} seconds", MAX_JOURNAL_MAX_AGE),
+            ));
+        }
+        if let Some(address) = self.http.as_deref().filter(|a| !a.is_empty()) {
+            if address.parse::<std::net::SocketAddr>().is_err() {
+                return Err((
+                    HTTP.name,
+                    format!(
+                        "expected address:port, e.g. 0.0.0.0:8080, got {:?}",
+                        address
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks every setting this layer has and writes it to `store`, stopping
+    /// at the first failure and returning the key that could not be stored.
+    pub fn save(
+        &self,
+        store: &mut dyn ConfigStore,
+    ) -> std::result::Result<(), (&'static str, std::io::Error)> {
+        self.check().map_err(|(key, message)| {
+            tracing::error!("Invalid value for {}: {}", key, message);
+            (
+                key,
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, message),
+            )
+        })?;
+        for (key, value) in self.entries() {
+            store.set(key, value).map_err(|e| (key, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Fully resolved settings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub log: String,
+    pub ip_log: String,
+    pub ip_log_format: OutputFormat,
+    pub time_delay: u64,
+    pub ipv6: Vec<Ipv6Scope>,
+    pub filters: Vec<FilterRule>,
+    pub heartbeat: bool,
+    pub watch: bool,
+    pub debounce_ms: u64,
+    pub write_retries: u32,
+    pub retry_delay_ms: u64,
+    pub history_len: u32,
+    pub history_max_age: u64,
+    pub journal: Option<String>,
+    pub journal_max_size: u64,
+    pub journal_max_age: u64,
+    pub health: Option<String>,
+    pub outputs: Vec<Output>,
+    /// Consecutive failed polls after which the service stops; 0 never stops.
+    pub max_failures: u32,
+    pub backoff_ms: u64,
+    /// Address and port of the HTTP endpoint, if it is on.
+    pub http: Option<String>,
+    /// Files the settings were read from, watched for changes by the service.
+    pub files: Vec<PathBuf>,
+}
+
+impl Config {
+    pub fn resolve(service_name: &str, layer: ConfigLayer) -> Config {
+        let retry = RetryPolicy::default();
+        Config {
+            log: layer
+                .log
+                .unwrap_or_else(|| format!("{}.log.txt", service_name)),
+            ip_log: layer
+                .ip_log
+                .unwrap_or_else(|| format!("{}.ip_log.txt", service_name)),
+            ip_log_format: layer.ip_log_format.unwrap_or_default(),
+            time_delay: layer.time_delay.unwrap_or(DEFAULT_POLL_RATE),
+            ipv6: layer.ipv6.unwrap_or_default(),
+            filters: layer.filters.unwrap_or_default(),
+            heartbeat: layer.heartbeat.unwrap_or(false),
+            watch: layer.watch.unwrap_or(true),
+            debounce_ms: layer.debounce_ms.unwrap_or(DEFAULT_DEBOUNCE_MS),
+            write_retries: layer.write_retries.unwrap_or(retry.retries),
+            retry_delay_ms: layer
+                .retry_delay_ms
+                .unwrap_or(retry.delay.as_millis() as u64),
+            history_len: layer
+                .history_len
+                .unwrap_or(HistoryPolicy::default().depth as u32),
+            history_max_age: layer.history_max_age.unwrap_or(0),
+            journal: layer.journal.filter(|path| !path.is_empty()),
+            journal_max_size: layer.journal_max_size.unwrap_or(DEFAULT_JOURNAL_MAX_SIZE),
+            journal_max_age: layer.journal_max_age.unwrap_or(0),
+            health: match layer.health {
+                Some(path) => Some(path).filter(|path| !path.is_empty()),
+                None => Some(health::default_path(service_name)),
+            },
+            outputs: layer.outputs.unwrap_or_default(),
+            max_failures: layer.max_failures.unwrap_or(0),
+            backoff_ms: layer.backoff_ms.unwrap_or(DEFAULT_BACKOFF_MS),
+            http: layer.http.filter(|address| !address.is_empty()),
+            files: vec![],
+        }
+    }
+
+    /// The settings that differ in `new`, as `key: old -> new`.
+    pub fn changes(&self, new: &Config) -> Vec<String> {
+        ConfigLayer::from(self)
+            .entries()
+            .into_iter()
+            .zip(ConfigLayer::from(new).entries())
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((key, old), (_, new))| format!("{}: {} -> {}", key, old, new))
+            .collect()
+    }
+
+    pub fn poll_rate(&self) -> Duration {
+        Duration::from_secs(self.time_delay)
+    }
+
+    /// Delay before retrying after `failures` failed polls in a row: the
+    /// backoff doubled per failure, never longer than the poll rate. A backoff
+    /// of 0 retries at the poll rate.
+    pub fn retry_delay(&self, failures: u32) -> Duration {
+        if self.backoff_ms == 0 {
+            return self.poll_rate();
+        }
+        let factor = 1u64 << failures.saturating_sub(1).min(20);
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor)).min(self.poll_rate())
+    }
+
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms)
+    }
+
+    pub fn address_filter(&self) -> AddressFilter {
+        AddressFilter {
+            ipv6: self.ipv6.clone(),
+            rules: self.filters.clone(),
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.write_retries,
+            delay: Duration::from_millis(self.retry_delay_ms),
+        }
+    }
+
+    /// A max age of 0 seconds means samples never expire.
+    pub fn history_policy(&self) -> HistoryPolicy {
+        HistoryPolicy {
+            depth: (self.history_len as usize).max(1),
+            max_age: (self.history_max_age > 0).then(|| Duration::from_secs(self.history_max_age)),
+        }
+    }
+
+    /// 0 disables either rotation trigger.
+    pub fn journal_rotation(&self) -> journal::Rotation {
+        journal::Rotation {
+            max_size: (self.journal_max_size > 0).then_some(self.journal_max_size),
+            max_age: (self.journal_max_age > 0).then(|| Duration::from_secs(self.journal_max_age)),
+        }
+    }
+}
+
+impl From<&Config> for ConfigLayer {
+    fn from(config: &Config) -> Self {
+        ConfigLayer {
+            log: Some(config.log.clone()),
+            ip_log: Some(config.ip_log.clone()),
+            ip_log_format: Some(config.ip_log_format),
+            time_delay: Some(config.time_delay),
+            ipv6: Some(config.ipv6.clone()),
+            filters: Some(config.filters.clone()),
+            heartbeat: Some(config.heartbeat),
+            watch: Some(config.watch),
+            debounce_ms: Some(config.debounce_ms),
+            write_retries: Some(config.write_retries),
+            retry_delay_ms: Some(config.retry_delay_ms),
+            history_len: Some(config.history_len),
+            history_max_age: Some(config.history_max_age),
+            journal: Some(config.journal.clone().unwrap_or_default()),
+            journal_max_size: Some(config.journal_max_size),
+            journal_max_age: Some(config.journal_max_age),
+            health: Some(config.health.clone().unwrap_or_default()),
+            outputs: Some(config.outputs.clone()),
+            max_failures: Some(config.max_failures),
+            backoff_ms: Some(config.backoff_ms),
+            http: Some(config.http.clone().unwrap_or_default()),
+        }
+    }
+}
+
+/// The settings sources in precedence order, each named for messages.
+struct Sources {
+    /// The config file path and where it was set.
+    config_path: Option<(String, String)>,
+    layers: Vec<(String, ConfigLayer)>,
+    files: Vec<PathBuf>,
+}
+
+/// Reads every settings source. The config file is `config_path`, else
+/// `IP_TO_FILE_CONFIG`, else the path stored with `--config`.
+fn sources(service_name: &str, cli: &ConfigLayer, config_path: Option<&str>) -> Result<Sources> {
+    let system = store::system_store(service_name);
+    let config_path = match config_path {
+        Some(path) => Some((path.to_owned(), "command line".to_owned())),
+        None => match std::env::var(CONFIG_ENV) {
+            Ok(path) => Some((path, "environment".to_owned())),
+            Err(_) => CONFIG
+                .get(system.as_ref())
+                .map_err(crate::service::other_error)?
+                .map(|path| (path, system.name())),
+        },
+    };
+    let config_path = config_path.filter(|(path, _)| !path.is_empty());
+
+    cli.check()
+        .map_err(|(key, message)| ConfigError {
+            source: "command line".to_owned(),
+            key: Some(key.to_owned()),
+            message,
+        })
+        .map_err(config_error)?;
+    let env = ConfigLayer::from_env(std::env::vars()).map_err(config_error)?;
+    let file = match &config_path {
+        Some((path, _)) => ConfigLayer::from_file(Path::new(path)).map_err(config_error)?,
+        None => ConfigLayer::default(),
+    };
+    let stored = ConfigLayer::from_store(system.as_ref()).map_err(config_error)?;
+
+    let files = system
+        .path()
+        .map(Path::to_path_buf)
+        .into_iter()
+        .chain(config_path.as_ref().map(|(path, _)| PathBuf::from(path)))
+        .collect();
+    let mut layers = vec![
+        ("command line".to_owned(), cli.clone()),
+        ("environment".to_owned(), env),
+    ];
+    if let Some((path, _)) = &config_path {
+        layers.push((path.clone(), file));
+    }
+    layers.push((system.name(), stored));
+
+    Ok(Sources {
+        config_path,
+        layers,
+        files,
+    })
+}
+
+/// Resolves the settings with the precedence CLI, environment, config file,
+/// system store, defaults.
+pub fn load(service_name: &str, cli: &ConfigLayer, config_path: Option<&str>) -> Result<Config> {
+    let sources = sources(service_name, cli, config_path)?;
+    let layer = sources
+        .layers
+        .into_iter()
+        .fold(ConfigLayer::default(), |layer, (_, lower)| layer.or(lower));
+    let mut config = Config::resolve(service_name, layer);
+    config.files = sources.files;
+    Ok(config)
+}
+
+/// One setting in effect and where its value came from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Effective {
+    pub key: &'static str,
+    pub value: Value,
+    pub source: String,
+}
+
+/// Every setting as `load` would resolve it, with its source: a layer's
+/// name or "default". The config file path comes first if there is one.
+pub fn effective(
+    service_name: &str,
+    cli: &ConfigLayer,
+    config_path: Option<&str>,
+) -> Result<Vec<Effective>> {
+    let sources = sources(service_name, cli, config_path)?;
+    let layers: Vec<(String, Vec<(&'static str, Value)>)> = sources
+        .layers
+        .into_iter()
+        .map(|(name, layer)| (name, layer.entries()))
+        .collect();
+    let defaults = ConfigLayer::from(&Config::resolve(service_name, ConfigLayer::default()));
+
+    let mut settings: Vec<Effective> = sources
+        .config_path
+        .map(|(path, source)| Effective {
+            key: CONFIG.name,
+            value: Value::String(path),
+            source,
+        })
+        .into_iter()
+        .collect();
+    for (key, default) in defaults.entries() {
+        let found = layers.iter().find_map(|(name, entries)| {
+            entries
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, value)| (value.clone(), name.clone()))
+        });
+        let (value, source) = found.unwrap_or((default, "default".to_owned()));
+        settings.push(Effective { key, value, source });
+    }
+    Ok(settings)
+}
+
+/// Parses one setting given as text, as `config set` does. Lists can be
+/// separated like in the environment.
+pub fn parse(key: &str, value: &str) -> std::result::Result<ConfigLayer, String> {
+    let mut layer = ConfigLayer::default();
+    layer.set(key, &Value::String(value.to_owned()))?;
+    layer.check().map_err(|(_, message)| message)?;
+    Ok(layer)
+}
+
+/// Loads the settings again for the running service, from the same `cli`
+/// and `config_path` it started with, and returns them if anything changed,
+/// logging each changed setting. If they can't be loaded the current settings
+/// stay in effect.
+pub fn reload(
+    service_name: &str,
+    cli: &ConfigLayer,
+    config_path: Option<&str>,
+    current: &Config,
+) -> Option<Config> {
+    let new = match load(service_name, cli, config_path) {
+        Ok(new) => new,
+        // `load` has logged why.
+        Err(_) => {
+            tracing::warn!("Keeping the current settings");
+            return None;
+        }
+    };
+    if new == *current {
+        return None;
+    }
+    for change in current.changes(&new) {
+        tracing::info!("Setting changed: {}", change);
+    }
+    Some(new)
+}
+
+fn config_error(e: ConfigError) -> crate::service::Error {
+    tracing::error!("Invalid configuration: {}", e);
+    crate::service::other_error(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn from_toml(content: &str, source: &str) -> std::result::Result<ConfigLayer, ConfigError> {
+        let table: toml::Table = content.parse().unwrap();
+        ConfigLayer::from_store(&MemoryStore::from(table).named(source))
+    }
+
+    #[test]
+    fn file_values_parse() {
+        let layer = from_toml(
+            r#"
+            ip_log = 'C:\ProgramData\ip.json'
+            ip_log_format = "json"
+            time_delay = 60
+            ipv6 = ["global", "unique-local"]
+            filters = ["deny name:vEthernet*", "allow cidr:10.0.0.0/8"]
+            heartbeat = true
+            "#,
+            "test.toml",
+        )
+        .unwrap();
+        assert_eq!(layer.ip_log.as_deref(), Some(r"C:\ProgramData\ip.json"));
+        assert_eq!(layer.ip_log_format, Some(OutputFormat::Json));
+        assert_eq!(layer.time_delay, Some(60));
+        assert_eq!(
+            layer.ipv6,
+            Some(vec![Ipv6Scope::UniqueLocal, Ipv6Scope::Global])
+        );
+        assert_eq!(layer.filters.unwrap().len(), 2);
+        assert_eq!(layer.heartbeat, Some(true));
+        assert_eq!(layer.watch, None);
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let err = from_toml("time_delay = \"soon\"", "a.toml").unwrap_err();
+        assert_eq!(err.key.as_deref(), Some("time_delay"));
+        assert_eq!(
+            err.to_string(),
+            "a.toml: time_delay: expected a number, got \"soon\""
+        );
+
+        let err = from_toml("history_len = 0", "a.toml").unwrap_err();
+        assert_eq!(err.key.as_deref(), Some("history_len"));
+
+        let err = from_toml("poll = 5", "a.toml").unwrap_err();
+        assert_eq!(err.to_string(), "a.toml: poll: unknown setting");
+
+        let vars = [("IP_TO_FILE_FILTERS".to_owned(), "deny mac:*".to_owned())];
+        let err = ConfigLayer::from_env(vars.into_iter()).unwrap_err();
+        assert_eq!(err.key.as_deref(), Some("IP_TO_FILE_FILTERS"));
+    }
+
+    #[test]
+    fn typed_keys_round_trip() {
+        let mut store = MemoryStore::default();
+        TIME_DELAY.set(&mut store, &60).unwrap();
+        HEARTBEAT.set(&mut store, &true).unwrap();
+        FILTERS
+            .set(&mut store, &vec!["deny type:tunnel".parse().unwrap()])
+            .unwrap();
+        assert_eq!(TIME_DELAY.get(&store).unwrap(), Some(60));
+        assert_eq!(HEARTBEAT.get(&store).unwrap(), Some(true));
+        assert_eq!(WATCH.get(&store).unwrap(), None);
+
+        // Older versions stored booleans as numbers and lists as strings.
+        store.set("watch", Value::Integer(0)).unwrap();
+        store
+            .set("filters", Value::from("deny type:tunnel;allow name:eth*"))
+            .unwrap();
+        assert_eq!(WATCH.get(&store).unwrap(), Some(false));
+        assert_eq!(FILTERS.get(&store).unwrap().unwrap().len(), 2);
+
+        store.set("time_delay", Value::from("soon")).unwrap();
+        let err = TIME_DELAY.get(&store).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(ConfigLayer::from_store(&store).is_err());
+
+        let layer = ConfigLayer {
+            ip_log: Some("ip.txt".to_owned()),
+            ..Default::default()
+        };
+        let mut saved = MemoryStore::default();
+        layer.save(&mut saved).unwrap();
+        assert_eq!(ConfigLayer::from_store(&saved).unwrap(), layer);
+    }
+
+    #[test]
+    fn layers_follow_precedence() {
+        let vars = [
+            ("IP_TO_FILE_TIME_DELAY".to_owned(), "30".to_owned()),
+            ("IP_TO_FILE_HEARTBEAT".to_owned(), "true".to_owned()),
+            ("IP_TO_FILE_CONFIG".to_owned(), "ignored.toml".to_owned()),
+            ("PATH".to_owned(), "/bin".to_owned()),
+        ];
+        let cli = ConfigLayer {
+            time_delay: Some(10),
+            ..Default::default()
+        };
+        let env = ConfigLayer::from_env(vars.into_iter()).unwrap();
+        let file = from_toml("heartbeat = false\nwatch = false", "f").unwrap();
+        let registry = ConfigLayer {
+            watch: Some(true),
+            debounce_ms: Some(500),
+            ..Default::default()
+        };
+
+        let config = Config::resolve("svc", cli.or(env).or(file).or(registry));
+        assert_eq!(config.time_delay, 10);
+        assert!(config.heartbeat);
+        assert!(!config.watch);
+        assert_eq!(config.debounce_ms, 500);
+        assert_eq!(config.ip_log, "svc.ip_log.txt");
+        assert_eq!(config.write_retries, RetryPolicy::default().retries);
+    }
+
+    #[test]
+    fn changes_name_the_settings() {
+        let old = Config::resolve("svc", ConfigLayer::default());
+        let layer = from_toml("time_delay = 60\njournal = 'changes.jsonl'", "f").unwrap();
+        let new = Config::resolve("svc", layer);
+        assert_eq!(
+            old.changes(&new),
+            vec![
+                "time_delay: 900 -> 60".to_owned(),
+                "journal: \"\" -> \"changes.jsonl\"".to_owned(),
+            ]
+        );
+        assert!(new.changes(&new).is_empty());
+    }
+
+    #[test]
+    fn parse_checks_the_value() {
+        let layer = parse("filters", "deny type:tunnel;allow name:eth*").unwrap();
+        assert_eq!(layer.filters.unwrap().len(), 2);
+        assert_eq!(
+            parse("time_delay", "0").unwrap_err(),
+            "must be at least 1 second"
+        );
+        assert_eq!(parse("poll", "5").unwrap_err(), "unknown setting");
+        assert!(parse("journal_max_size", "18446744073709551615").is_err());
+        assert!(parse("journal_max_age", "1000000000000").is_err());
+
+        let cli = ConfigLayer {
+            journal_max_size: Some(u64::MAX),
+            ..Default::default()
+        };
+        let mut store = MemoryStore::default();
+        assert_eq!(cli.save(&mut store).unwrap_err().0, "journal_max_size");
+        assert_eq!(store.get("journal_max_size").unwrap(), None);
+    }
+
+    #[test]
+    fn retry_delay_backs_off_up_to_the_poll_rate() {
+        let layer = from_toml("time_delay = 60\nbackoff_ms = 5000", "f").unwrap();
+        let config = Config::resolve("svc", layer);
+        assert_eq!(config.retry_delay(1), Duration::from_secs(5));
+        assert_eq!(config.retry_delay(3), Duration::from_secs(20));
+        assert_eq!(config.retry_delay(5), Duration::from_secs(60));
+        assert_eq!(config.retry_delay(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn outputs_parse_from_tables_and_text() {
+        let layer = from_toml(
+            r#"
+            [[outputs]]
+            path = '\\server\share\ips.json'
+            format = "json"
+            filters = ["deny type:tunnel"]
+
+            [[outputs]]
+            path = "ips.txt"
+            "#,
+            "f",
+        )
+        .unwrap();
+        let outputs = layer.outputs.unwrap();
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0].format, Some(OutputFormat::Json));
+        assert_eq!(outputs[1].heartbeat, None);
+
+        let text = outputs.to_value().to_string();
+        assert_eq!(parse("outputs", &text).unwrap().outputs.unwrap(), outputs);
+
+        let config = Config::resolve("svc", ConfigLayer::default());
+        assert_eq!(outputs[1].format(&config), OutputFormat::Debug);
+        assert_eq!(outputs[0].address_filter(&config).rules.len(), 1);
+
+        assert!(parse("outputs", "[{ format = 'json' }]").is_err());
+        assert!(parse("outputs", "[{ path = 'a', colour = 'red' }]").is_err());
+    }
+}
@@ -0,0 +1,420 @@
+use crate::source::Adapter;
+use glob::{MatchOptions, Pattern};
+use ipnet::IpNet;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// Classes of IPv6 address that can be reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Ipv6Scope {
+    /// fe80::/10, only reachable on the local link
+    LinkLocal,
+    /// fc00::/7, routable inside the site only
+    UniqueLocal,
+    /// Global unicast, 2000::/3
+    Global,
+    /// Temporary (privacy extension) addresses of any scope, Linux only
+    Temporary,
+}
+
+impl Ipv6Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Ipv6Scope::LinkLocal => "link-local",
+            Ipv6Scope::UniqueLocal => "unique-local",
+            Ipv6Scope::Global => "global",
+            Ipv6Scope::Temporary => "temporary",
+        }
+    }
+
+    /// The scope of a stable (non temporary) address, or `None` for
+    /// addresses that are never reported such as loopback and multicast.
+    pub fn of(ip: &Ipv6Addr) -> Option<Ipv6Scope> {
+        let first = ip.segments()[0];
+        if first & 0xffc0 == 0xfe80 {
+            Some(Ipv6Scope::LinkLocal)
+        } else if first & 0xfe00 == 0xfc00 {
+            Some(Ipv6Scope::UniqueLocal)
+        } else if first & 0xe000 == 0x2000 {
+            Some(Ipv6Scope::Global)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Ipv6Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Ipv6Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Ipv6Scope as clap::ValueEnum>::from_str(s.trim(), true)
+    }
+}
+
+/// Parses a comma separated scope list as stored in the service settings.
+pub fn parse_ipv6_scopes(s: &str) -> Result<Vec<Ipv6Scope>, String> {
+    let mut scopes = s
+        .split(',')
+        .filter(|scope| !scope.trim().is_empty())
+        .map(Ipv6Scope::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+    scopes.sort();
+    scopes.dedup();
+    Ok(scopes)
+}
+
+pub fn format_ipv6_scopes(scopes: &[Ipv6Scope]) -> String {
+    scopes
+        .iter()
+        .map(Ipv6Scope::as_str)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuleAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuleMatch {
+    /// Address lies inside the network
+    Cidr(IpNet),
+    /// Glob against the adapter name
+    Name(Pattern),
+    /// Glob against the adapter description
+    Description(Pattern),
+    /// Glob against the adapter kind (`ethernet`, `wireless`, `tunnel`, ...)
+    Type(Pattern),
+}
+
+/// One `allow`/`deny` line of the filter rule list, e.g.
+/// `deny name:vEthernet*` or `allow cidr:10.0.0.0/8`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterRule {
+    pub action: RuleAction,
+    pub matcher: RuleMatch,
+}
+
+impl FilterRule {
+    pub fn matches(&self, adapter: &Adapter, ip: &IpAddr) -> bool {
+        let options = MatchOptions {
+            case_sensitive: false,
+            ..Default::default()
+        };
+        match &self.matcher {
+            RuleMatch::Cidr(net) => net.contains(ip),
+            RuleMatch::Name(p) => p.matches_with(&adapter.name, options),
+            RuleMatch::Description(p) => p.matches_with(&adapter.description, options),
+            RuleMatch::Type(p) => p.matches_with(adapter.kind.as_str(), options),
+        }
+    }
+}
+
+impl fmt::Display for FilterRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            RuleAction::Allow => "allow",
+            RuleAction::Deny => "deny",
+        };
+        match &self.matcher {
+            RuleMatch::Cidr(net) => write!(f, "{} cidr:{}", action, net),
+            RuleMatch::Name(p) => write!(f, "{} name:{}", action, p),
+            RuleMatch::Description(p) => write!(f, "{} desc:{}", action, p),
+            RuleMatch::Type(p) => write!(f, "{} type:{}", action, p),
+        }
+    }
+}
+
+impl FromStr for FilterRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (action, rest) = s
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("expected '<allow|deny> <field>:<pattern>', got {:?}", s))?;
+        let action = match action.to_ascii_lowercase().as_str() {
+            "allow" => RuleAction::Allow,
+            "deny" => RuleAction::Deny,
+            other => return Err(format!("unknown rule action {:?}", other)),
+        };
+        let (field, pattern) = rest
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("expected '<field>:<pattern>', got {:?}", rest.trim()))?;
+        let glob = |pattern: &str| Pattern::new(pattern).map_err(|e| e.to_string());
+        let matcher = match field.to_ascii_lowercase().as_str() {
+            "cidr" => RuleMatch::Cidr(pattern.parse().map_err(|e| format!("{}", e))?),
+            "name" => RuleMatch::Name(glob(pattern)?),
+            "desc" => RuleMatch::Description(glob(pattern)?),
+            "type" => RuleMatch::Type(glob(pattern)?),
+            other => return Err(format!("unknown rule field {:?}", other)),
+        };
+        Ok(FilterRule { action, matcher })
+    }
+}
+
+/// Decides which adapter addresses end up in the IP log.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AddressFilter {
+    /// IPv6 scopes to report; IPv6 is off when empty.
+    pub ipv6: Vec<Ipv6Scope>,
+    /// Rules evaluated in order after the built-in checks; the first match
+    /// decides and an address no rule matches is kept.
+    pub rules: Vec<FilterRule>,
+}
+
+impl AddressFilter {
+    pub fn keep(&self, adapter: &Adapter, ip: &IpAddr) -> bool {
+        self.decide(adapter, ip).keep()
+    }
+
+    /// Like `keep`, but says which check or rule decided.
+    pub fn decide(&self, adapter: &Adapter, ip: &IpAddr) -> Decision<'_> {
+        if ip.is_loopback() {
+            return Decision::Loopback;
+        }
+        if ip.is_multicast() {
+            return Decision::Multicast;
+        }
+        if ip.is_unspecified() {
+            return Decision::Unspecified;
+        }
+        if let IpAddr::V6(v6) = ip {
+            let scope = if adapter.temporary.contains(ip) {
+                Some(Ipv6Scope::Temporary)
+            } else {
+                Ipv6Scope::of(v6)
+            };
+            if !scope.is_some_and(|scope| self.ipv6.contains(&scope)) {
+                return Decision::Ipv6Scope(scope);
+            }
+        }
+        match self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(adapter, ip))
+        {
+            Some((index, rule)) => Decision::Rule(index + 1, rule),
+            None => Decision::NoRule,
+        }
+    }
+}
+
+/// The check that kept or dropped an address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decision<'a> {
+    Loopback,
+    Multicast,
+    Unspecified,
+    /// An IPv6 address of a scope not in `ipv6`; `None` if it has no scope
+    /// we report at all.
+    Ipv6Scope(Option<Ipv6Scope>),
+    /// The first matching rule and its 1-based position in the list.
+    Rule(usize, &'a FilterRule),
+    /// No rule matched, so the address is kept.
+    NoRule,
+}
+
+impl Decision<'_> {
+    pub fn keep(&self) -> bool {
+        match self {
+            Decision::Rule(_, rule) => rule.action == RuleAction::Allow,
+            Decision::NoRule => true,
+            _ => false,
+        }
+    }
+
+    /// Short machine readable name of the check.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Decision::Loopback => "loopback",
+            Decision::Multicast => "multicast",
+            Decision::Unspecified => "unspecified",
+            Decision::Ipv6Scope(_) => "ipv6-scope",
+            Decision::Rule(..) => "rule",
+            Decision::NoRule => "no-rule",
+        }
+    }
+}
+
+impl fmt::Display for Decision<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decision::Loopback => f.write_str("loopback address"),
+            Decision::Multicast => f.write_str("multicast address"),
+            Decision::Unspecified => f.write_str("unspecified address"),
+            Decision::Ipv6Scope(Some(scope)) => write!(f, "ipv6 scope {} not enabled", scope),
+            Decision::Ipv6Scope(None) => f.write_str("ipv6 scope not reportable"),
+            Decision::Rule(index, rule) => write!(f, "rule {}: {}", index, rule),
+            Decision::NoRule => f.write_str("no rule matched"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::AdapterKind;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn adapter(name: &str, description: &str, kind: AdapterKind) -> Adapter {
+        Adapter {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            kind,
+            temporary: vec![ip("2001:db8::abcd")],
+            ..Default::default()
+        }
+    }
+
+    fn rules(rules: &[&str]) -> Vec<FilterRule> {
+        rules.iter().map(|r| r.parse().unwrap()).collect()
+    }
+
+    /// Which (adapter, address) pairs of a typical Windows dev box survive.
+    fn kept(filter: &AddressFilter) -> Vec<&'static str> {
+        let machine = [
+            (
+                adapter("Ethernet", "Intel(R) Ethernet", AdapterKind::Ethernet),
+                "192.168.1.20",
+            ),
+            (
+                adapter("Wi-Fi", "Intel(R) Wi-Fi 6", AdapterKind::Wireless),
+                "10.0.0.5",
+            ),
+            (
+                adapter(
+                    "vEthernet (WSL)",
+                    "Hyper-V Virtual Ethernet Adapter",
+                    AdapterKind::Ethernet,
+                ),
+                "172.28.160.1",
+            ),
+            (adapter("docker0", "", AdapterKind::Ethernet), "172.17.0.1"),
+            (
+                adapter("VPN", "WireGuard Tunnel", AdapterKind::Tunnel),
+                "10.8.0.2",
+            ),
+        ];
+        machine
+            .iter()
+            .filter(|(a, addr)| filter.keep(a, &ip(addr)))
+            .map(|(_, addr)| *addr)
+            .collect()
+    }
+
+    #[test]
+    fn ipv6_is_opt_in() {
+        let filter = AddressFilter::default();
+        let eth = adapter("eth0", "", AdapterKind::Ethernet);
+        assert!(filter.keep(&eth, &ip("192.168.1.20")));
+        assert!(!filter.keep(&eth, &ip("2001:db8::1")));
+        assert!(!filter.keep(&eth, &ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn ipv6_scopes_select_addresses() {
+        let filter = AddressFilter {
+            ipv6: parse_ipv6_scopes("global,unique-local").unwrap(),
+            ..Default::default()
+        };
+        let eth = adapter("eth0", "", AdapterKind::Ethernet);
+        assert!(filter.keep(&eth, &ip("2001:db8::1")));
+        assert!(filter.keep(&eth, &ip("fd12:3456::1")));
+        assert!(!filter.keep(&eth, &ip("fe80::1")));
+        assert!(!filter.keep(&eth, &ip("2001:db8::abcd")));
+        assert!(!filter.keep(&eth, &ip("::1")));
+        assert!(!filter.keep(&eth, &ip("ff02::1")));
+    }
+
+    #[test]
+    fn no_rules_keeps_everything() {
+        let filter = AddressFilter::default();
+        assert_eq!(kept(&filter).len(), 5);
+    }
+
+    #[test]
+    fn deny_rules_drop_virtual_adapters() {
+        let filter = AddressFilter {
+            rules: rules(&[
+                "deny desc:*Hyper-V*",
+                "deny cidr:172.16.0.0/12",
+                "deny type:tunnel",
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(kept(&filter), vec!["192.168.1.20", "10.0.0.5"]);
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let filter = AddressFilter {
+            rules: rules(&["allow name:wi-fi", "deny cidr:10.0.0.0/8", "deny name:v*"]),
+            ..Default::default()
+        };
+        assert_eq!(
+            kept(&filter),
+            vec!["192.168.1.20", "10.0.0.5", "172.17.0.1"]
+        );
+    }
+
+    #[test]
+    fn decide_names_the_deciding_check() {
+        let filter = AddressFilter {
+            rules: rules(&["allow name:eth0", "deny cidr:10.0.0.0/8"]),
+            ..Default::default()
+        };
+        let eth = adapter("eth0", "", AdapterKind::Ethernet);
+        let wlan = adapter("wlan0", "", AdapterKind::Wireless);
+        assert_eq!(filter.decide(&eth, &ip("127.0.0.1")), Decision::Loopback);
+        assert_eq!(
+            filter.decide(&eth, &ip("2001:db8::abcd")),
+            Decision::Ipv6Scope(Some(Ipv6Scope::Temporary))
+        );
+        let allowed = filter.decide(&eth, &ip("10.0.0.5"));
+        assert!(allowed.keep());
+        assert_eq!(allowed.to_string(), "rule 1: allow name:eth0");
+        let denied = filter.decide(&wlan, &ip("10.0.0.6"));
+        assert!(!denied.keep());
+        assert_eq!(denied.to_string(), "rule 2: deny cidr:10.0.0.0/8");
+        assert_eq!(filter.decide(&wlan, &ip("192.168.1.2")), Decision::NoRule);
+    }
+
+    #[test]
+    fn rules_round_trip() {
+        for rule in [
+            "allow cidr:10.0.0.0/8",
+            "deny name:vEthernet*",
+            "deny type:tunnel",
+        ] {
+            assert_eq!(rule.parse::<FilterRule>().unwrap().to_string(), rule);
+        }
+        assert!("deny cidr:10.0.0.0/33".parse::<FilterRule>().is_err());
+        assert!("block name:eth0".parse::<FilterRule>().is_err());
+        assert!("deny mac:*".parse::<FilterRule>().is_err());
+    }
+
+    #[test]
+    fn scopes_round_trip() {
+        let scopes = parse_ipv6_scopes("temporary, link-local").unwrap();
+        assert_eq!(scopes, vec![Ipv6Scope::LinkLocal, Ipv6Scope::Temporary]);
+        assert_eq!(format_ipv6_scopes(&scopes), "link-local,temporary");
+        assert!(parse_ipv6_scopes("site-local").is_err());
+    }
+}
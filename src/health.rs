@@ -0,0 +1,255 @@
+use crate::{
+    service::State,
+    writer::{self, RetryPolicy},
+};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, time::Duration};
+use time::OffsetDateTime;
+
+/// What the running service last did, written after every poll so `status`
+/// can tell a service that runs from one that runs but fails.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Health {
+    pub pid: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub started: OffsetDateTime,
+    /// Last poll that read the adapters and wrote everything it had to.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_success: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_write: Option<OffsetDateTime>,
+    pub last_error: Option<HealthError>,
+    /// Addresses in the newest sample.
+    pub addresses: usize,
+    #[serde(default)]
+    pub polls: u64,
+    #[serde(default)]
+    pub failures: u64,
+    /// Failed polls since the last successful one.
+    #[serde(default)]
+    pub consecutive_failures: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthError {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub message: String,
+}
+
+/// Where the health record goes unless the `health` setting says otherwise.
+/// It has to be absolute, since `status` runs from another directory.
+#[cfg(windows)]
+pub fn default_path(service_name: &str) -> String {
+    let program_data = std::env::var("ProgramData").unwrap_or_else(|_| "C:\\ProgramData".into());
+    format!("{}\\{}\\health.json", program_data, service_name)
+}
+
+#[cfg(target_os = "linux")]
+pub fn default_path(service_name: &str) -> String {
+    format!("/var/lib/{}/health.json", service_name)
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            pid: std::process::id(),
+            started: OffsetDateTime::now_utc(),
+            last_success: None,
+            last_write: None,
+            last_error: None,
+            addresses: 0,
+            polls: 0,
+            failures: 0,
+            consecutive_failures: 0,
+        }
+    }
+
+    /// Records the outcome of one poll; `Ok(true)` means the IP file was written.
+    pub fn record(&mut self, result: &std::io::Result<bool>, addresses: usize) {
+        let now = OffsetDateTime::now_utc();
+        self.polls += 1;
+        match result {
+            Ok(written) => {
+                self.consecutive_failures = 0;
+                self.last_success = Some(now);
+                if *written {
+                    self.last_write = Some(now);
+                }
+                self.addresses = addresses;
+            }
+            Err(e) => {
+                self.failures += 1;
+                self.consecutive_failures += 1;
+                self.last_error = Some(HealthError {
+                    timestamp: now,
+                    message: e.to_string(),
+                })
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                tracing::error!("Failed to create {}: {}", dir.display(), e);
+                return Err(e);
+            }
+        }
+        let content = serde_json::to_vec_pretty(self).map_err(std::io::Error::other)?;
+        if let Err(e) = writer::write_atomic(path, &content, RetryPolicy::default()) {
+            tracing::error!("Failed to write health record {}: {}", path.display(), e);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let content = std::fs::read(path)?;
+        serde_json::from_slice(&content)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// The overall answer of `status`, also its exit code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    Healthy,
+    /// Running, but the last poll failed, the record is stale or missing.
+    Unhealthy,
+    NotRunning,
+    NotInstalled,
+}
+
+impl Verdict {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Verdict::Healthy => 0,
+            Verdict::Unhealthy => 2,
+            Verdict::NotRunning => 3,
+            Verdict::NotInstalled => 4,
+        }
+    }
+}
+
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Verdict::Healthy => "healthy",
+            Verdict::Unhealthy => "unhealthy",
+            Verdict::NotRunning => "not running",
+            Verdict::NotInstalled => "not installed",
+        })
+    }
+}
+
+/// Judges a service from its state and health record. A record from another
+/// process or without a successful poll in two intervals is stale. An
+/// interval too long to add to the date never makes the record stale.
+pub fn check(
+    state: State,
+    pid: Option<u32>,
+    health: Option<&Health>,
+    poll_rate: Duration,
+    now: OffsetDateTime,
+) -> Verdict {
+    match state {
+        State::NotInstalled => return Verdict::NotInstalled,
+        State::Running => (),
+        _ => return Verdict::NotRunning,
+    }
+    let Some(health) = health else {
+        return Verdict::Unhealthy;
+    };
+    if pid.is_some_and(|pid| pid != health.pid) {
+        return Verdict::Unhealthy;
+    }
+    let Some(last_success) = health.last_success else {
+        return Verdict::Unhealthy;
+    };
+    let stale_at = time::Duration::try_from(poll_rate)
+        .ok()
+        .and_then(|poll_rate| poll_rate.checked_mul(2))
+        .and_then(|grace| last_success.checked_add(grace));
+    if stale_at.is_some_and(|stale_at| stale_at < now) {
+        return Verdict::Unhealthy;
+    }
+    match &health.last_error {
+        Some(error) if error.timestamp > last_success => Verdict::Unhealthy,
+        _ => Verdict::Healthy,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_needs_a_recent_successful_poll() {
+        let poll_rate = Duration::from_secs(60);
+        let mut health = Health::new();
+        let now = health.started;
+        let check = |health: &Health, now| {
+            check(
+                State::Running,
+                Some(health.pid),
+                Some(health),
+                poll_rate,
+                now,
+            )
+        };
+
+        assert_eq!(check(&health, now), Verdict::Unhealthy);
+        health.record(&Ok(true), 2);
+        let polled = health.last_success.unwrap();
+        assert_eq!(check(&health, polled), Verdict::Healthy);
+        assert_eq!(
+            check(&health, polled + Duration::from_secs(180)),
+            Verdict::Unhealthy
+        );
+
+        health.record(&Err(std::io::Error::other("disk full")), 0);
+        assert_eq!(check(&health, polled), Verdict::Unhealthy);
+        assert_eq!(health.addresses, 2);
+        assert_eq!((health.polls, health.failures), (2, 1));
+        assert_eq!(health.consecutive_failures, 1);
+
+        assert_eq!(
+            super::check(State::Stopped, None, Some(&health), poll_rate, now),
+            Verdict::NotRunning
+        );
+
+        let mut health = Health::new();
+        health.record(&Ok(true), 2);
+        for poll_rate in [Duration::from_secs(1_000_000_000_000), Duration::MAX] {
+            assert_eq!(
+                super::check(
+                    State::Running,
+                    Some(health.pid),
+                    Some(&health),
+                    poll_rate,
+                    now + Duration::from_secs(86400)
+                ),
+                Verdict::Healthy
+            );
+        }
+        assert_eq!(Verdict::NotInstalled.exit_code(), 4);
+    }
+
+    #[test]
+    fn health_round_trips() {
+        let path = std::env::temp_dir().join(format!("ip_to_file_health_{}", std::process::id()));
+        let mut health = Health::new();
+        health.record(&Err(std::io::Error::other("no adapters")), 0);
+        health.save(&path.join("health.json")).unwrap();
+        assert_eq!(Health::load(&path.join("health.json")).unwrap(), health);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
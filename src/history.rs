@@ -0,0 +1,173 @@
+use crate::source::{Adapter, AdapterKind, OperStatus};
+use serde::Serialize;
+use std::{net::IpAddr, time::Duration};
+use time::OffsetDateTime;
+
+/// How much address history the service keeps and reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistoryPolicy {
+    /// Maximum number of samples, at least one.
+    pub depth: usize,
+    /// Samples not seen for longer than this are dropped.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for HistoryPolicy {
+    fn default() -> Self {
+        Self {
+            depth: 4,
+            max_age: None,
+        }
+    }
+}
+
+impl HistoryPolicy {
+    /// Drops samples beyond the depth or older than the max age and returns
+    /// whether any were removed. The newest sample describes the current
+    /// addresses and is always kept. A max age reaching back before the
+    /// earliest representable date keeps everything.
+    pub fn trim(&self, hist: &mut Vec<Sample>, now: OffsetDateTime) -> bool {
+        let before = hist.len();
+        let cutoff = self.max_age.and_then(|max_age| {
+            time::Duration::try_from(max_age)
+                .ok()
+                .and_then(|max_age| now.checked_sub(max_age))
+        });
+        if let Some(cutoff) = cutoff {
+            let newest = hist.len().saturating_sub(1);
+            let mut i = 0;
+            hist.retain(|sample| {
+                i += 1;
+                i > newest || sample.last_seen >= cutoff
+            });
+        }
+        let depth = self.depth.max(1);
+        if hist.len() > depth {
+            hist.drain(0..hist.len() - depth);
+        }
+        hist.len() != before
+    }
+}
+
+/// The kept addresses of one adapter, and what the adapter looked like, at
+/// the time of a sample.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AdapterRecord {
+    pub name: String,
+    pub description: String,
+    #[serde(rename = "type")]
+    pub kind: AdapterKind,
+    pub status: OperStatus,
+    pub mac: Option<String>,
+    pub addresses: Vec<IpAddr>,
+    pub gateways: Vec<IpAddr>,
+    pub dns_servers: Vec<IpAddr>,
+}
+
+impl AdapterRecord {
+    pub fn new(adapter: Adapter, addresses: Vec<IpAddr>) -> Self {
+        Self {
+            name: adapter.name,
+            description: adapter.description,
+            kind: adapter.kind,
+            status: adapter.status,
+            mac: adapter.mac,
+            addresses,
+            gateways: adapter.gateways,
+            dns_servers: adapter.dns_servers,
+        }
+    }
+}
+
+/// One set of addresses and the polls that observed it: `timestamp` is when
+/// the set first appeared and `last_seen` the latest poll that still saw it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Sample {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_seen: OffsetDateTime,
+    pub adapters: Vec<AdapterRecord>,
+}
+
+impl Sample {
+    pub fn new(timestamp: OffsetDateTime, adapters: Vec<AdapterRecord>) -> Self {
+        Self {
+            timestamp,
+            last_seen: timestamp,
+            adapters,
+        }
+    }
+
+    /// Every address in the sample, sorted and without duplicates.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        let mut ip_addrs: Vec<IpAddr> = self
+            .adapters
+            .iter()
+            .flat_map(|adapter| adapter.addresses.iter().copied())
+            .collect();
+        ip_addrs.sort();
+        ip_addrs.dedup();
+        ip_addrs
+    }
+
+    /// Whether both samples have the same addresses on the same adapters,
+    /// ignoring when they were taken and any other adapter details.
+    pub fn same_addresses(&self, other: &Sample) -> bool {
+        self.adapters.len() == other.adapters.len()
+            && self
+                .adapters
+                .iter()
+                .zip(&other.adapters)
+                .all(|(a, b)| a.name == b.name && a.addresses == b.addresses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hist(minutes_ago: &[i64], now: OffsetDateTime) -> Vec<Sample> {
+        minutes_ago
+            .iter()
+            .map(|m| Sample::new(now - time::Duration::minutes(*m), vec![]))
+            .collect()
+    }
+
+    #[test]
+    fn trim_keeps_depth_newest() {
+        let now = OffsetDateTime::UNIX_EPOCH + Duration::from_secs(86400);
+        let mut h = hist(&[50, 40, 30, 20, 10], now);
+        let policy = HistoryPolicy {
+            depth: 2,
+            max_age: None,
+        };
+        assert!(policy.trim(&mut h, now));
+        assert_eq!(h, hist(&[20, 10], now));
+        assert!(!policy.trim(&mut h, now));
+    }
+
+    #[test]
+    fn trim_drops_old_but_keeps_current() {
+        let now = OffsetDateTime::UNIX_EPOCH + Duration::from_secs(86400);
+        let policy = HistoryPolicy {
+            depth: 10,
+            max_age: Some(Duration::from_secs(25 * 60)),
+        };
+
+        let mut h = hist(&[50, 40, 20, 10], now);
+        assert!(policy.trim(&mut h, now));
+        assert_eq!(h, hist(&[20, 10], now));
+
+        let mut h = hist(&[90], now);
+        assert!(!policy.trim(&mut h, now));
+        assert_eq!(h.len(), 1);
+
+        let forever = HistoryPolicy {
+            depth: 10,
+            max_age: Some(Duration::from_secs(1_000_000_000_000)),
+        };
+        let mut h = hist(&[50, 40], now);
+        assert!(!forever.trim(&mut h, now));
+    }
+}
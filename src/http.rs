@@ -0,0 +1,203 @@
+use crate::{health::Health, history::Sample, output::OutputFormat};
+use std::{
+    sync::{Arc, RwLock},
+    thread::JoinHandle,
+};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// What the HTTP endpoint serves, replaced by the service loop after every
+/// poll with the history the IP file is rendered from.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    pub history: Vec<Sample>,
+    pub health: Option<Health>,
+}
+
+/// The optional HTTP listener, serving `/ips`, `/history` and `/health` from
+/// the shared snapshot on its own thread until dropped.
+pub struct HttpServer {
+    server: Arc<Server>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HttpServer {
+    pub fn start(address: &str, snapshot: Arc<RwLock<Snapshot>>) -> std::io::Result<Self> {
+        let server = match Server::http(address) {
+            Ok(server) => Arc::new(server),
+            Err(e) => {
+                tracing::error!("Failed to listen on {}: {}", address, e);
+                return Err(std::io::Error::other(e));
+            }
+        };
+        tracing::info!("Serving addresses on http://{}", address);
+
+        let listener = server.clone();
+        let thread = std::thread::spawn(move || {
+            for request in listener.incoming_requests() {
+                handle(request, &snapshot);
+            }
+        });
+        Ok(HttpServer {
+            server,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Status code, content type and body of a response.
+type Reply = (u16, &'static str, String);
+
+fn handle(request: Request, snapshot: &RwLock<Snapshot>) {
+    let accept = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Accept"))
+        .map(|h| h.value.as_str().to_owned());
+    let (status, content_type, body) = match request.method() {
+        Method::Get | Method::Head => match snapshot.read() {
+            Ok(snapshot) => route(request.url(), accept.as_deref(), &snapshot),
+            Err(e) => {
+                tracing::error!("Failed to read the snapshot: {}", e);
+                (500, "text/plain", "snapshot unavailable\n".to_owned())
+            }
+        },
+        _ => (
+            405,
+            "text/plain",
+            "only GET and HEAD are supported\n".to_owned(),
+        ),
+    };
+    tracing::debug!("HTTP {} {} -> {}", request.method(), request.url(), status);
+
+    let mut response = Response::from_string(body).with_status_code(status);
+    if let Ok(header) = Header::from_bytes("Content-Type", content_type) {
+        response.add_header(header);
+    }
+    if let Err(e) = request.respond(response) {
+        tracing::warn!("Failed to send HTTP response: {}", e);
+    }
+}
+
+/// `/ips` is the newest sample, JSON unless the client accepts only plain
+/// text; `/history` every sample, JSON by default. Both take `?format=` with
+/// any IP file format. `/health` is the health record, 503 while polls fail.
+fn route(url: &str, accept: Option<&str>, snapshot: &Snapshot) -> Reply {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let format = match query.split('&').find_map(|p| p.strip_prefix("format=")) {
+        Some(format) => match format.parse::<OutputFormat>() {
+            Ok(format) => Some(format),
+            Err(e) => return (400, "text/plain", format!("{}\n", e)),
+        },
+        None => None,
+    };
+
+    match path {
+        "/ips" => {
+            let plain = accept.is_some_and(|a| a.contains("text/plain") && !a.contains("json"));
+            let default = if plain {
+                OutputFormat::Lines
+            } else {
+                OutputFormat::Json
+            };
+            let history = &snapshot.history;
+            render(
+                format.unwrap_or(default),
+                &history[history.len().saturating_sub(1)..],
+            )
+        }
+        "/history" => render(format.unwrap_or(OutputFormat::Json), &snapshot.history),
+        "/health" => match &snapshot.health {
+            Some(health) => {
+                let status = if health.last_success.is_some() && health.consecutive_failures == 0 {
+                    200
+                } else {
+                    503
+                };
+                match serde_json::to_string_pretty(health) {
+                    Ok(body) => (status, "application/json", body),
+                    Err(e) => (500, "text/plain", format!("{}\n", e)),
+                }
+            }
+            None => (503, "text/plain", "no poll yet\n".to_owned()),
+        },
+        _ => (
+            404,
+            "text/plain",
+            "not found; try /ips, /history or /health\n".to_owned(),
+        ),
+    }
+}
+
+fn render(format: OutputFormat, history: &[Sample]) -> Reply {
+    match format.render(history) {
+        Ok(body) => (200, format.content_type(), body),
+        Err(e) => {
+            tracing::error!("Failed to render {} output: {}", format, e);
+            (500, "text/plain", format!("{}\n", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{history::AdapterRecord, source::Adapter};
+    use time::OffsetDateTime;
+
+    fn snapshot() -> Snapshot {
+        let sample = |ip: &str| {
+            Sample::new(
+                OffsetDateTime::now_utc(),
+                vec![AdapterRecord::new(
+                    Adapter {
+                        name: "eth0".to_owned(),
+                        ..Default::default()
+                    },
+                    vec![ip.parse().unwrap()],
+                )],
+            )
+        };
+        let mut health = Health::new();
+        health.record(&Ok(true), 1);
+        Snapshot {
+            history: vec![sample("10.0.0.5"), sample("192.168.1.20")],
+            health: Some(health),
+        }
+    }
+
+    #[test]
+    fn ips_serves_the_newest_sample() {
+        let snapshot = snapshot();
+        let (status, content_type, body) = route("/ips", None, &snapshot);
+        assert_eq!((status, content_type), (200, "application/json"));
+        assert!(body.contains("192.168.1.20") && !body.contains("10.0.0.5"));
+
+        let plain = route("/ips", Some("text/plain"), &snapshot);
+        assert_eq!(plain.2, "192.168.1.20\n");
+        assert_eq!(route("/ips?format=hosts", None, &snapshot).1, "text/plain");
+        assert_eq!(route("/ips?format=xml", None, &snapshot).0, 400);
+
+        assert!(route("/history", None, &snapshot).2.contains("10.0.0.5"));
+        assert_eq!(route("/", None, &snapshot).0, 404);
+    }
+
+    #[test]
+    fn health_fails_while_polls_fail() {
+        let mut snapshot = snapshot();
+        assert_eq!(route("/health", None, &snapshot).0, 200);
+        if let Some(health) = &mut snapshot.health {
+            health.record(&Err(std::io::Error::other("no adapters")), 0);
+        }
+        assert_eq!(route("/health", None, &snapshot).0, 503);
+        assert_eq!(route("/health", None, &Snapshot::default()).0, 503);
+    }
+}
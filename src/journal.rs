@@ -0,0 +1,255 @@
+use crate::history::Sample;
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Rotated journals kept next to the live one (`<path>.1` is the newest).
+const ROTATED_FILES: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Removed,
+    /// Assigned when the service started, a snapshot rather than a change.
+    Present,
+}
+
+/// One line of the change journal.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct JournalEvent {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub event: Change,
+    pub adapter: String,
+    pub address: IpAddr,
+}
+
+/// The add/remove events that turn `prev` into `next`, removals first. With
+/// no previous sample (service start) every address is reported as present.
+pub fn diff(prev: Option<&Sample>, next: &Sample) -> Vec<JournalEvent> {
+    let pairs = |sample: &Sample| -> Vec<(String, IpAddr)> {
+        sample
+            .adapters
+            .iter()
+            .flat_map(|a| a.addresses.iter().map(|ip| (a.name.clone(), *ip)))
+            .collect()
+    };
+    let old = prev.map(pairs).unwrap_or_default();
+    let new = pairs(next);
+
+    let event = |event: Change, (adapter, address): &(String, IpAddr)| JournalEvent {
+        timestamp: next.timestamp,
+        event,
+        adapter: adapter.clone(),
+        address: *address,
+    };
+    let removed = old
+        .iter()
+        .filter(|p| !new.contains(p))
+        .map(|p| event(Change::Removed, p));
+    let change = if prev.is_some() {
+        Change::Added
+    } else {
+        Change::Present
+    };
+    let added = new
+        .iter()
+        .filter(|p| !old.contains(p))
+        .map(|p| event(change, p));
+    removed.chain(added).collect()
+}
+
+/// When the live journal is rotated; `None` disables that trigger.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+/// Appends `events` as JSON lines, rotating the journal first if it is too
+/// big or its first entry too old.
+pub fn append(path: &Path, events: &[JournalEvent], rotation: Rotation) -> std::io::Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    if needs_rotation(path, rotation, OffsetDateTime::now_utc()) {
+        if let Err(e) = rotate(path) {
+            tracing::error!("Failed to rotate journal {}: {}", path.display(), e);
+            return Err(e);
+        }
+    }
+
+    let mut content = String::new();
+    for event in events {
+        content.push_str(&serde_json::to_string(event).map_err(std::io::Error::other)?);
+        content.push('\n');
+    }
+
+    let mut file = match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            tracing::error!("Failed to open journal {}: {}", path.display(), e);
+            return Err(e);
+        }
+    };
+    if let Err(e) = file.write_all(content.as_bytes()) {
+        tracing::error!("Failed to append to journal {}: {}", path.display(), e);
+        return Err(e);
+    }
+    file.sync_data()
+}
+
+fn needs_rotation(path: &Path, rotation: Rotation, now: OffsetDateTime) -> bool {
+    let size = match std::fs::metadata(path) {
+        Ok(m) => m.len(),
+        Err(_) => return false,
+    };
+    if size == 0 {
+        return false;
+    }
+    if rotation.max_size.is_some_and(|max| size >= max) {
+        return true;
+    }
+    match rotation.max_age {
+        Some(max_age) => first_timestamp(path).is_some_and(|first| {
+            time::Duration::try_from(max_age)
+                .ok()
+                .and_then(|max_age| first.checked_add(max_age))
+                .is_some_and(|due| due <= now)
+        }),
+        None => false,
+    }
+}
+
+fn first_timestamp(path: &Path) -> Option<OffsetDateTime> {
+    #[derive(serde::Deserialize)]
+    struct Line {
+        timestamp: String,
+    }
+
+    let mut line = String::new();
+    BufReader::new(File::open(path).ok()?)
+        .read_line(&mut line)
+        .ok()?;
+    let line: Line = serde_json::from_str(&line).ok()?;
+    OffsetDateTime::parse(&line.timestamp, &Rfc3339).ok()
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Moves the journal to `<path>.1`, shifting older ones up. Nothing to do if
+/// there is no journal yet.
+pub fn rotate(path: &Path) -> std::io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    tracing::info!("Rotating journal {}", path.display());
+    let _ = std::fs::remove_file(rotated(path, ROTATED_FILES));
+    for n in (1..ROTATED_FILES).rev() {
+        let from = rotated(path, n);
+        if from.exists() {
+            std::fs::rename(&from, rotated(path, n + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated(path, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{history::AdapterRecord, source::Adapter};
+
+    fn sample(minutes: i64, adapters: &[(&str, &[&str])]) -> Sample {
+        Sample::new(
+            OffsetDateTime::UNIX_EPOCH + time::Duration::minutes(minutes),
+            adapters
+                .iter()
+                .map(|(name, addresses)| {
+                    AdapterRecord::new(
+                        Adapter {
+                            name: (*name).to_owned(),
+                            ..Default::default()
+                        },
+                        addresses.iter().map(|a| a.parse().unwrap()).collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn diff_reports_adds_and_removes() {
+        let first = sample(0, &[("eth0", &["192.168.1.20"])]);
+        let events = diff(None, &first);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, Change::Present);
+
+        let second = sample(5, &[("eth0", &["192.168.1.21"]), ("wg0", &["10.8.0.2"])]);
+        let events: Vec<_> = diff(Some(&first), &second)
+            .into_iter()
+            .map(|e| (e.event, e.adapter, e.address.to_string()))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (
+                    Change::Removed,
+                    "eth0".to_owned(),
+                    "192.168.1.20".to_owned()
+                ),
+                (Change::Added, "eth0".to_owned(), "192.168.1.21".to_owned()),
+                (Change::Added, "wg0".to_owned(), "10.8.0.2".to_owned()),
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&diff(None, &first)[0]).unwrap(),
+            r#"{"timestamp":"1970-01-01T00:00:00Z","event":"present","adapter":"eth0","address":"192.168.1.20"}"#
+        );
+    }
+
+    #[test]
+    fn append_rotates_by_size_and_age() {
+        let dir = std::env::temp_dir().join(format!("ip_to_file_journal_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("journal.jsonl");
+        let events = diff(None, &sample(0, &[("eth0", &["192.168.1.20"])]));
+
+        let by_size = Rotation {
+            max_size: Some(1),
+            max_age: None,
+        };
+        append(&path, &events, by_size).unwrap();
+        append(&path, &events, by_size).unwrap();
+        assert!(rotated(&path, 1).exists());
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        let by_age = Rotation {
+            max_size: None,
+            max_age: Some(Duration::from_secs(60)),
+        };
+        assert!(needs_rotation(&path, by_age, OffsetDateTime::now_utc()));
+        assert!(!needs_rotation(
+            &path,
+            by_age,
+            OffsetDateTime::UNIX_EPOCH + Duration::from_secs(30)
+        ));
+        let never = Rotation {
+            max_size: None,
+            max_age: Some(Duration::from_secs(u64::MAX)),
+        };
+        assert!(!needs_rotation(&path, never, OffsetDateTime::now_utc()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,18 +1,65 @@
-#[cfg(windows)]
+#[cfg(any(windows, target_os = "linux"))]
+mod cli;
+
+#[cfg(any(windows, target_os = "linux"))]
+mod config;
+
+#[cfg(any(windows, target_os = "linux"))]
+mod filter;
+
+#[cfg(any(windows, target_os = "linux"))]
+mod health;
+
+#[cfg(any(windows, target_os = "linux"))]
+mod history;
+
+#[cfg(any(windows, target_os = "linux"))]
+mod http;
+
+#[cfg(any(windows, target_os = "linux"))]
+mod journal;
+
+#[cfg(any(windows, target_os = "linux"))]
+mod output;
+
+#[cfg(any(windows, target_os = "linux"))]
+mod poller;
+
+#[cfg(any(windows, target_os = "linux"))]
+mod source;
+
+#[cfg(any(windows, target_os = "linux"))]
 mod service;
 
-#[cfg(windows)]
+#[cfg(any(windows, target_os = "linux"))]
+mod store;
+
+#[cfg(any(windows, target_os = "linux"))]
 mod utils;
 
+#[cfg(any(windows, target_os = "linux"))]
+mod watch;
+
+#[cfg(any(windows, target_os = "linux"))]
+mod writer;
+
 use clap::Parser;
 
 const SERVICE_NAME: &str = "ip_to_file_service";
 const SERVICE_DISPLAY_NAME: &str = "IP to File Service";
-const SERVICE_DISCRIPTION: &str = "Windows Service to put IP list in a file for IP Discovery";
+const SERVICE_DISCRIPTION: &str = "Service to put IP list in a file for IP Discovery";
+
+#[cfg(windows)]
+const SERVICE_EXE_NAME: &str = "ip_to_file.exe";
+#[cfg(not(windows))]
+const SERVICE_EXE_NAME: &str = "ip_to_file";
 
 #[derive(Parser, Debug)]
 #[clap(name = SERVICE_NAME, about = SERVICE_DISCRIPTION)]
 struct Opt {
+    #[clap(subcommand)]
+    command: Option<cli::Command>,
+
     #[clap(short = 'i', long = "install", default_value_t = false)]
     install: bool,
 
@@ -22,12 +69,125 @@ struct Opt {
     #[clap(short = 'r', long = "restart", default_value_t = false)]
     restart: bool,
 
+    /// TOML config file whose settings override the stored ones; also read
+    /// from IP_TO_FILE_CONFIG. "" stops using it
+    #[clap(short = 'c', long = "config")]
+    config: Option<String>,
+
+    /// Print the settings in effect and where each comes from, then exit
+    #[clap(long = "show-config", default_value_t = false)]
+    show_config: bool,
+
     #[clap(short = 'l', long = "log")]
     log_file: Option<String>,
 
     #[clap(short = 'o', long = "output")]
     ip_log_file: Option<String>,
 
+    #[clap(short = 'f', long = "format", value_enum)]
+    ip_log_format: Option<output::OutputFormat>,
+
+    /// Report IPv6 addresses of these scopes (comma separated)
+    #[clap(long = "ipv6", value_enum, value_delimiter = ',')]
+    ipv6_scopes: Option<Vec<filter::Ipv6Scope>>,
+
+    /// Stop reporting IPv6 addresses
+    #[clap(
+        long = "no-ipv6",
+        default_value_t = false,
+        conflicts_with = "ipv6_scopes"
+    )]
+    no_ipv6: bool,
+
+    /// Ordered address filter rule, e.g. "deny name:vEthernet*" or
+    /// "allow cidr:10.0.0.0/8" (fields: cidr, name, desc, type). Repeat for
+    /// more rules; replaces the stored list
+    #[clap(long = "filter")]
+    filters: Vec<filter::FilterRule>,
+
+    /// Remove all address filter rules
+    #[clap(
+        long = "no-filters",
+        default_value_t = false,
+        conflicts_with = "filters"
+    )]
+    no_filters: bool,
+
+    /// A further IP file as an inline TOML table, e.g.
+    /// '{ path = "ip.json", format = "json", filters = ["deny type:tunnel"] }'
+    /// (also ipv6, heartbeat, write_retries, retry_delay_ms). Repeat for more;
+    /// replaces the stored list
+    #[clap(long = "extra-output")]
+    outputs: Vec<config::Output>,
+
+    /// Remove all further IP files
+    #[clap(
+        long = "no-extra-outputs",
+        default_value_t = false,
+        conflicts_with = "outputs"
+    )]
+    no_outputs: bool,
+
+    /// Rewrite the IP file on every poll even when the addresses are unchanged
+    #[clap(long = "heartbeat")]
+    heartbeat: Option<bool>,
+
+    /// Poll as soon as the OS reports an address change (default true); the
+    /// -t interval still applies as a fallback
+    #[clap(long = "watch")]
+    watch: Option<bool>,
+
+    /// Milliseconds to wait for further changes after a notification
+    #[clap(long = "debounce")]
+    debounce_ms: Option<u64>,
+
+    /// Times to retry replacing the IP file while a reader has it locked
+    #[clap(long = "retries")]
+    write_retries: Option<u32>,
+
+    /// Milliseconds between those retries
+    #[clap(long = "retry-delay")]
+    retry_delay_ms: Option<u64>,
+
+    /// Number of address sets kept in the IP file (default 4)
+    #[clap(long = "history", value_parser = clap::value_parser!(u32).range(1..))]
+    history_len: Option<u32>,
+
+    /// Drop address sets not seen for this many seconds; 0 keeps them
+    #[clap(long = "max-age")]
+    history_max_age: Option<u64>,
+
+    /// Append every address add/remove to this JSON Lines file; "" disables it
+    #[clap(long = "journal")]
+    journal: Option<String>,
+
+    /// Rotate the journal at this many bytes (default 10 MiB); 0 disables
+    #[clap(long = "journal-max-size")]
+    journal_max_size: Option<u64>,
+
+    /// Rotate the journal once its first entry is this many seconds old; 0 disables
+    #[clap(long = "journal-max-age")]
+    journal_max_age: Option<u64>,
+
+    /// Stop the service after this many failed polls in a row; 0 (default)
+    /// keeps retrying
+    #[clap(long = "max-failures")]
+    max_failures: Option<u32>,
+
+    /// Milliseconds before the first retry after a failed poll, doubled per
+    /// further failure up to the -t interval (default 5000)
+    #[clap(long = "backoff")]
+    backoff_ms: Option<u64>,
+
+    /// Serve /ips, /history and /health over HTTP on this address:port, e.g.
+    /// 0.0.0.0:8080; "" (default) turns it off
+    #[clap(long = "http")]
+    http: Option<String>,
+
+    /// Health record read by `status`; "" disables it
+    #[clap(long = "health")]
+    health: Option<String>,
+
     #[clap(short = 't', long = "time")]
     time_delay: Option<u64>,
 
@@ -35,87 +195,65 @@ struct Opt {
     verbose: bool,
 }
 
-#[cfg(windows)]
-fn main() -> windows_service::Result<()> {
+#[cfg(any(windows, target_os = "linux"))]
+fn main() -> service::Result<()> {
     let opt = Opt::parse();
-
-    println!("Setting default log path");
-    if let Err(e) = utils::set_default_log_path(SERVICE_NAME) {
-        if !opt.install {
-            eprintln!("Error setting default log path: {} {}", SERVICE_NAME, e);
-            return Err(windows_service::Error::Winapi(std::io::Error::other(e)));
-        }
-    }
-    if let Some(log_file_path) = opt.log_file.clone() {
-        if let Err(e) = utils::set_log_path(SERVICE_NAME, &log_file_path) {
-            if !opt.install {
-                eprintln!(
-                    "Error setting log path: {} {} {}",
-                    SERVICE_NAME, &log_file_path, e
-                );
-                return Err(windows_service::Error::Winapi(std::io::Error::other(e)));
-            }
+    let result = run(&opt);
+    // Exit only now, once the service has shut down and everything is dropped.
+    if let Err(e) = &result {
+        if let Some(code) = service::exit_code(e) {
+            eprintln!("Error: {}", e);
+            std::process::exit(code as i32);
         }
     }
+    result
+}
 
-    utils::set_default_ip_log_path(SERVICE_NAME)?;
-    println!("Setting default ip log path");
-    if let Err(e) = utils::set_default_ip_log_path(SERVICE_NAME) {
-        if !opt.install {
-            eprintln!("Error setting default ip log path: {} {}", SERVICE_NAME, e);
-            return Err(windows_service::Error::Winapi(std::io::Error::other(e)));
-        }
+#[cfg(any(windows, target_os = "linux"))]
+fn run(opt: &Opt) -> service::Result<()> {
+    let cli = cli_layer(opt);
+
+    if let Some(command) = &opt.command {
+        return cli::run(SERVICE_NAME, command, &cli, opt.config.as_deref());
     }
-    if let Some(log_file_path) = opt.ip_log_file.clone() {
-        utils::set_ip_log_path(SERVICE_NAME, &log_file_path)?;
-        if let Err(e) = utils::set_ip_log_path(SERVICE_NAME, &log_file_path) {
-            if !opt.install {
-                eprintln!(
-                    "Error setting ip log path: {} {} {}",
-                    SERVICE_NAME, &log_file_path, e
-                );
-                return Err(windows_service::Error::Winapi(std::io::Error::other(e)));
-            }
-        }
+    if opt.show_config {
+        return cli::show_config(SERVICE_NAME, &cli, opt.config.as_deref());
     }
 
-    println!("Getting log path");
-    let log_path: Option<&str>;
-    let log_path_holder;
-    if let Ok(Some(log_path_val)) = utils::get_log_path(SERVICE_NAME) {
-        log_path_holder = log_path_val;
-        log_path = Some(&log_path_holder);
-    } else {
-        log_path = None;
+    println!("Saving settings");
+    let mut store = store::system_store(SERVICE_NAME);
+    if let Err((key, e)) = cli.save(store.as_mut()) {
+        if !opt.install {
+            eprintln!("Error setting {}: {} {}", key, SERVICE_NAME, e);
+            return Err(service::other_error(e));
+        }
     }
 
-    println!("Setting time delay");
-
-    if let Some(td) = opt.time_delay {
-        if let Err(e) = utils::set_time_delay(SERVICE_NAME, td) {
+    if let Some(path) = &opt.config {
+        let result = if path.is_empty() {
+            store.unset(config::CONFIG.name)
+        } else {
+            config::CONFIG.set(store.as_mut(), path)
+        };
+        if let Err(e) = result {
             if !opt.install {
-                eprintln!("Error setting time delay: {} {} {}", SERVICE_NAME, td, e);
-                return Err(windows_service::Error::Winapi(std::io::Error::other(e)));
+                eprintln!("Error setting config: {} {} {}", SERVICE_NAME, path, e);
+                return Err(service::other_error(e));
             }
         }
     }
 
-    println!("Getting time delay");
-    let time_delay_res = utils::get_time_delay(SERVICE_NAME);
-    let time_delay = match time_delay_res {
-        Ok(result) => result,
+    println!("Loading config");
+    let config = match config::load(SERVICE_NAME, &cli, opt.config.as_deref()) {
+        Ok(config) => config,
         Err(e) => {
-            if !opt.install {
-                eprintln!("Error getting time delay: {} {}", SERVICE_NAME, e);
-                return Err(windows_service::Error::Winapi(std::io::Error::other(e)));
-            } else {
-                None
-            }
+            eprintln!("Error loading config: {} {}", SERVICE_NAME, e);
+            return Err(e);
         }
     };
 
     println!("Logging");
-    if let Err(e) = utils::logging(log_path) {
+    if let Err(e) = utils::logging(Some(&config.log)) {
         eprintln!("Error logging: {} {}", SERVICE_NAME, e);
     }
 
@@ -125,7 +263,7 @@ fn main() -> windows_service::Result<()> {
     if opt.install {
         tracing::info!("Installing Service");
         service::install_service(
-            "ip_to_file.exe",
+            SERVICE_EXE_NAME,
             SERVICE_NAME,
             SERVICE_DISPLAY_NAME,
             SERVICE_DISCRIPTION,
@@ -135,17 +273,52 @@ fn main() -> windows_service::Result<()> {
         service::uninstall_service(SERVICE_NAME)
     } else if opt.restart {
         tracing::info!("Restarting Service");
-        service::restart_service(SERVICE_NAME)
-    } else if opt.log_file.is_some() || opt.time_delay.is_some() || opt.ip_log_file.is_some() {
+        service::restart_service(
+            SERVICE_NAME,
+            std::time::Duration::from_secs(service::DEFAULT_TIMEOUT_SECS),
+        )
+    } else if opt.config.is_some() || cli != config::ConfigLayer::default() {
         // No other action to take
         Ok(())
     } else {
         tracing::info!("Running Service");
-        service::run(SERVICE_NAME, time_delay)
+        service::run(SERVICE_NAME)
     }
 }
 
-#[cfg(not(windows))]
+/// The settings given on the command line, the highest precedence layer.
+#[cfg(any(windows, target_os = "linux"))]
+fn cli_layer(opt: &Opt) -> config::ConfigLayer {
+    config::ConfigLayer {
+        log: opt.log_file.clone(),
+        ip_log: opt.ip_log_file.clone(),
+        ip_log_format: opt.ip_log_format,
+        time_delay: opt.time_delay,
+        ipv6: if opt.no_ipv6 {
+            Some(vec![])
+        } else {
+            opt.ipv6_scopes.clone()
+        },
+        filters: (opt.no_filters || !opt.filters.is_empty()).then(|| opt.filters.clone()),
+        heartbeat: opt.heartbeat,
+        watch: opt.watch,
+        debounce_ms: opt.debounce_ms,
+        write_retries: opt.write_retries,
+        retry_delay_ms: opt.retry_delay_ms,
+        history_len: opt.history_len,
+        history_max_age: opt.history_max_age,
+        journal: opt.journal.clone(),
+        journal_max_size: opt.journal_max_size,
+        journal_max_age: opt.journal_max_age,
+        health: opt.health.clone(),
+        http: opt.http.clone(),
+        max_failures: opt.max_failures,
+        backoff_ms: opt.backoff_ms,
+        outputs: (opt.no_outputs || !opt.outputs.is_empty()).then(|| opt.outputs.clone()),
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
 fn main() {
-    panic!("This program is only intended to run on Windows.");
+    panic!("This program is only intended to run on Windows or Linux.");
 }
@@ -0,0 +1,290 @@
+use crate::history::Sample;
+use serde::Serialize;
+use std::{fmt, net::IpAddr, str::FromStr};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Version of the structured (JSON, YAML, TOML) document layout. Bump when a
+/// field is renamed or removed; adding fields is backwards compatible.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Encoding of an IP log file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Rust debug listing of the address history (the original format)
+    #[default]
+    Debug,
+    /// Versioned JSON document with per-adapter samples
+    Json,
+    /// Versioned YAML document, same layout as JSON
+    Yaml,
+    /// Versioned TOML document, same layout as JSON
+    Toml,
+    /// One row per address and sample with the adapter details
+    Csv,
+    /// Current addresses, one per line
+    Lines,
+    /// Current addresses as `IP hostname` lines for a hosts file
+    Hosts,
+}
+
+impl OutputFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputFormat::Debug => "debug",
+            OutputFormat::Json => "json",
+            OutputFormat::Yaml => "yaml",
+            OutputFormat::Toml => "toml",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Lines => "lines",
+            OutputFormat::Hosts => "hosts",
+        }
+    }
+
+    /// MIME type of the rendered content.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Json => "application/json",
+            OutputFormat::Yaml => "application/yaml",
+            OutputFormat::Toml => "application/toml",
+            OutputFormat::Csv => "text/csv",
+            OutputFormat::Debug | OutputFormat::Lines | OutputFormat::Hosts => "text/plain",
+        }
+    }
+
+    pub fn render(&self, hist: &[Sample]) -> std::io::Result<String> {
+        match self {
+            OutputFormat::Debug => Ok(render_debug(hist)),
+            OutputFormat::Json => {
+                serde_json::to_string_pretty(&Report::new(hist)).map_err(std::io::Error::other)
+            }
+            OutputFormat::Yaml => {
+                serde_yaml::to_string(&Report::new(hist)).map_err(std::io::Error::other)
+            }
+            OutputFormat::Toml => {
+                toml::to_string_pretty(&Report::new(hist)).map_err(std::io::Error::other)
+            }
+            OutputFormat::Csv => render_csv(hist),
+            OutputFormat::Lines => Ok(render_lines(hist)),
+            OutputFormat::Hosts => Ok(render_hosts(hist)),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <OutputFormat as clap::ValueEnum>::from_str(s, true)
+    }
+}
+
+/// Top level of the structured documents.
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    schema_version: u32,
+    hostname: String,
+    #[serde(with = "time::serde::rfc3339")]
+    generated: OffsetDateTime,
+    history: &'a [Sample],
+}
+
+impl<'a> Report<'a> {
+    fn new(history: &'a [Sample]) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            hostname: hostname(),
+            generated: OffsetDateTime::now_utc(),
+            history,
+        }
+    }
+}
+
+fn hostname() -> String {
+    hostname::get()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn render_debug(hist: &[Sample]) -> String {
+    let ip_addr_hist: Vec<Vec<IpAddr>> = hist.iter().map(Sample::addresses).collect();
+    format!("{:#?}", &ip_addr_hist)
+}
+
+fn render_csv(hist: &[Sample]) -> std::io::Result<String> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record([
+        "timestamp",
+        "adapter",
+        "mac",
+        "address",
+        "description",
+        "type",
+        "status",
+        "gateways",
+        "dns_servers",
+        "last_seen",
+    ])?;
+    for sample in hist {
+        let timestamp = sample
+            .timestamp
+            .format(&Rfc3339)
+            .map_err(std::io::Error::other)?;
+        let last_seen = sample
+            .last_seen
+            .format(&Rfc3339)
+            .map_err(std::io::Error::other)?;
+        for adapter in &sample.adapters {
+            let gateways = join(&adapter.gateways);
+            let dns_servers = join(&adapter.dns_servers);
+            for ip in &adapter.addresses {
+                writer.write_record([
+                    timestamp.as_str(),
+                    adapter.name.as_str(),
+                    adapter.mac.as_deref().unwrap_or_default(),
+                    ip.to_string().as_str(),
+                    adapter.description.as_str(),
+                    adapter.kind.as_str(),
+                    adapter.status.as_str(),
+                    gateways.as_str(),
+                    dns_servers.as_str(),
+                    last_seen.as_str(),
+                ])?;
+            }
+        }
+    }
+    let bytes = writer.into_inner().map_err(std::io::Error::other)?;
+    String::from_utf8(bytes).map_err(std::io::Error::other)
+}
+
+/// Space separated list for a single CSV cell.
+fn join(ip_addrs: &[IpAddr]) -> String {
+    ip_addrs
+        .iter()
+        .map(IpAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn render_lines(hist: &[Sample]) -> String {
+    let mut content = String::new();
+    if let Some(sample) = hist.last() {
+        for ip in sample.addresses() {
+            content.push_str(&format!("{}\n", ip));
+        }
+    }
+    content
+}
+
+fn render_hosts(hist: &[Sample]) -> String {
+    let hostname = hostname();
+    let mut content = String::new();
+    if let Some(sample) = hist.last() {
+        for ip in sample.addresses() {
+            content.push_str(&format!("{}\t{}\n", ip, hostname));
+        }
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        history::AdapterRecord,
+        source::{Adapter, AdapterKind, OperStatus},
+    };
+
+    fn sample() -> Sample {
+        Sample::new(
+            OffsetDateTime::UNIX_EPOCH,
+            vec![AdapterRecord::new(
+                Adapter {
+                    name: "eth0".to_owned(),
+                    description: "Intel(R) Ethernet".to_owned(),
+                    kind: AdapterKind::Ethernet,
+                    status: OperStatus::Up,
+                    mac: Some("00:15:5D:01:02:03".to_owned()),
+                    gateways: vec!["192.168.1.1".parse().unwrap()],
+                    dns_servers: vec!["192.168.1.1".parse().unwrap(), "1.1.1.1".parse().unwrap()],
+                    ..Default::default()
+                },
+                vec!["192.168.1.20".parse().unwrap()],
+            )],
+        )
+    }
+
+    #[test]
+    fn json_has_versioned_schema() {
+        let content = OutputFormat::Json.render(&[sample()]).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&content).unwrap();
+
+        assert_eq!(value["schema_version"], SCHEMA_VERSION);
+        assert_eq!(value["history"][0]["timestamp"], "1970-01-01T00:00:00Z");
+        assert_eq!(value["history"][0]["last_seen"], "1970-01-01T00:00:00Z");
+        assert_eq!(value["history"][0]["adapters"][0]["name"], "eth0");
+        assert_eq!(
+            value["history"][0]["adapters"][0]["mac"],
+            "00:15:5D:01:02:03"
+        );
+        assert_eq!(
+            value["history"][0]["adapters"][0]["addresses"][0],
+            "192.168.1.20"
+        );
+        assert_eq!(value["history"][0]["adapters"][0]["type"], "ethernet");
+        assert_eq!(value["history"][0]["adapters"][0]["status"], "up");
+        assert_eq!(
+            value["history"][0]["adapters"][0]["gateways"][0],
+            "192.168.1.1"
+        );
+    }
+
+    #[test]
+    fn structured_formats_round_trip() {
+        let yaml = OutputFormat::Yaml.render(&[sample()]).unwrap();
+        let value: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(value["history"][0]["adapters"][0]["name"], "eth0");
+
+        let toml = OutputFormat::Toml.render(&[sample()]).unwrap();
+        let value: toml::Table = toml.parse().unwrap();
+        assert_eq!(value["schema_version"].as_integer(), Some(1));
+    }
+
+    #[test]
+    fn csv_has_row_per_address() {
+        let content = OutputFormat::Csv.render(&[sample()]).unwrap();
+        assert_eq!(
+            content,
+            "timestamp,adapter,mac,address,description,type,status,gateways,dns_servers,\
+             last_seen\n\
+             1970-01-01T00:00:00Z,eth0,00:15:5D:01:02:03,192.168.1.20,\
+             Intel(R) Ethernet,ethernet,up,192.168.1.1,192.168.1.1 1.1.1.1,\
+             1970-01-01T00:00:00Z\n"
+        );
+    }
+
+    #[test]
+    fn lines_lists_latest_sample() {
+        let content = OutputFormat::Lines.render(&[sample()]).unwrap();
+        assert_eq!(content, "192.168.1.20\n");
+    }
+
+    #[test]
+    fn hosts_maps_latest_sample_to_hostname() {
+        let mut old = sample();
+        old.adapters[0].addresses = vec!["10.0.0.5".parse().unwrap()];
+        let content = OutputFormat::Hosts.render(&[old, sample()]).unwrap();
+        assert_eq!(content, format!("192.168.1.20\t{}\n", hostname()));
+    }
+
+    #[test]
+    fn debug_matches_original_format() {
+        let content = OutputFormat::Debug.render(&[sample()]).unwrap();
+        assert_eq!(content, "[\n    [\n        192.168.1.20,\n    ],\n]");
+    }
+}
@@ -0,0 +1,408 @@
+use crate::{
+    config::{Config, Output},
+    filter::AddressFilter,
+    history::{AdapterRecord, HistoryPolicy, Sample},
+    journal::{self, JournalEvent, Rotation},
+    output::OutputFormat,
+    source::{self, Adapter, AddressSource},
+    writer::{self, RetryPolicy},
+};
+use std::path::Path;
+use time::OffsetDateTime;
+
+/// Adapter polling and IP file writing shared by the service backends.
+pub struct Poller {
+    source: Box<dyn AddressSource + Send>,
+    ip_addr_hist: Vec<Sample>,
+    /// Journal events not appended yet, kept until an append succeeds.
+    changes: Vec<JournalEvent>,
+    /// History of each further output by path, as its own filter sees it.
+    outputs: Vec<(String, Vec<Sample>)>,
+}
+
+impl Default for Poller {
+    fn default() -> Self {
+        Self::new(source::system_source())
+    }
+}
+
+impl Poller {
+    pub fn new(source: Box<dyn AddressSource + Send>) -> Self {
+        Self {
+            source,
+            ip_addr_hist: vec![],
+            changes: vec![],
+            outputs: vec![],
+        }
+    }
+
+    fn adapters(&self) -> std::io::Result<Vec<Adapter>> {
+        match self.source.adapters() {
+            Ok(adapters) => Ok(adapters),
+            Err(e) => {
+                tracing::error!("Failed to get network adapters: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Takes a new sample and returns whether the history changed, either
+    /// because the addresses differ from the previous sample or because old
+    /// samples were dropped. An unchanged sample only moves the previous
+    /// sample's `last_seen`.
+    pub fn poll(
+        &mut self,
+        filter: &AddressFilter,
+        history: &HistoryPolicy,
+    ) -> std::io::Result<bool> {
+        let adapters = self.adapters()?;
+        Ok(self.record(&adapters, filter, history))
+    }
+
+    fn record(
+        &mut self,
+        adapters: &[Adapter],
+        filter: &AddressFilter,
+        history: &HistoryPolicy,
+    ) -> bool {
+        let sample = sample(adapters, filter);
+        for record in &sample.adapters {
+            for ip in &record.addresses {
+                tracing::info!("IP: {} ({})", ip, record.name);
+            }
+        }
+
+        match self.ip_addr_hist.last() {
+            Some(last) if last.same_addresses(&sample) => (),
+            last => self.changes.extend(journal::diff(last, &sample)),
+        }
+        add_sample(&mut self.ip_addr_hist, sample, history)
+    }
+
+    /// One pass of the service loop: polls, journals the changes and writes
+    /// the IP file if the history changed, `force_write` is set or heartbeat
+    /// is on, then does the same for every further output. A failing output
+    /// doesn't stop the others; the first error is returned once all were
+    /// tried. Returns whether the IP file was written.
+    pub fn update(&mut self, config: &Config, force_write: bool) -> std::io::Result<bool> {
+        let adapters = self.adapters()?;
+        let changed = self.record(
+            &adapters,
+            &config.address_filter(),
+            &config.history_policy(),
+        );
+        let mut errors = vec![];
+
+        match &config.journal {
+            Some(path) => {
+                if let Err(e) = self.journal(path, config.journal_rotation()) {
+                    errors.push(e);
+                }
+            }
+            None => self.changes.clear(),
+        }
+
+        let write = changed || force_write || config.heartbeat;
+        if write {
+            if let Err(e) = self.write(&config.ip_log, config.ip_log_format, config.retry_policy())
+            {
+                errors.push(e);
+            }
+        }
+
+        self.outputs
+            .retain(|(path, _)| config.outputs.iter().any(|o| o.path == *path));
+        for output in &config.outputs {
+            if let Err(e) = self.update_output(config, output, &adapters, force_write) {
+                errors.push(e);
+            }
+        }
+
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(write),
+        }
+    }
+
+    fn update_output(
+        &mut self,
+        config: &Config,
+        output: &Output,
+        adapters: &[Adapter],
+        force_write: bool,
+    ) -> std::io::Result<()> {
+        let index = match self.outputs.iter().position(|(p, _)| *p == output.path) {
+            Some(index) => index,
+            None => {
+                self.outputs.push((output.path.clone(), vec![]));
+                self.outputs.len() - 1
+            }
+        };
+        let hist = &mut self.outputs[index].1;
+
+        let sample = sample(adapters, &output.address_filter(config));
+        let changed = add_sample(hist, sample, &config.history_policy());
+        if changed || force_write || output.heartbeat(config) {
+            write(
+                &output.path,
+                output.format(config),
+                output.retry_policy(config),
+                hist,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Addresses in the newest sample.
+    pub fn addresses(&self) -> usize {
+        self.ip_addr_hist.last().map_or(0, |s| s.addresses().len())
+    }
+
+    /// The address sets kept so far, oldest first.
+    pub fn history(&self) -> &[Sample] {
+        &self.ip_addr_hist
+    }
+
+    /// The IP file content for the history so far.
+    pub fn render(&self, format: OutputFormat) -> std::io::Result<String> {
+        render(format, &self.ip_addr_hist)
+    }
+
+    pub fn write(
+        &self,
+        odpath: &str,
+        format: OutputFormat,
+        policy: RetryPolicy,
+    ) -> std::io::Result<()> {
+        write(odpath, format, policy, &self.ip_addr_hist)
+    }
+
+    /// Appends the pending changes to the journal at `path`. If that fails
+    /// they stay pending for the next poll, so none are lost.
+    pub fn journal(&mut self, path: &str, rotation: Rotation) -> std::io::Result<()> {
+        journal::append(Path::new(path), &self.changes, rotation)?;
+        self.changes.clear();
+        Ok(())
+    }
+}
+
+/// The addresses `filter` keeps, sorted and without duplicates per adapter.
+fn sample(adapters: &[Adapter], filter: &AddressFilter) -> Sample {
+    let mut records: Vec<AdapterRecord> = vec![];
+
+    for adapter in adapters {
+        let mut ip_addrs = adapter.addresses.clone();
+        ip_addrs.sort();
+        ip_addrs.dedup();
+        ip_addrs.retain(|ip| filter.keep(adapter, ip));
+        if !ip_addrs.is_empty() {
+            let adapter = Adapter {
+                addresses: vec![],
+                ..adapter.clone()
+            };
+            records.push(AdapterRecord::new(adapter, ip_addrs));
+        }
+    }
+    Sample::new(OffsetDateTime::now_utc(), records)
+}
+
+/// Appends `sample` to `hist` unless it has the same addresses as the newest
+/// sample, then trims. Returns whether the history changed.
+fn add_sample(hist: &mut Vec<Sample>, sample: Sample, history: &HistoryPolicy) -> bool {
+    let now = sample.timestamp;
+    let changed = match hist.last_mut() {
+        Some(last) if last.same_addresses(&sample) => {
+            tracing::debug!("Addresses unchanged since {}", last.timestamp);
+            last.last_seen = now;
+            false
+        }
+        _ => {
+            hist.push(sample);
+            true
+        }
+    };
+
+    let trimmed = history.trim(hist, now);
+
+    changed || trimmed
+}
+
+fn render(format: OutputFormat, hist: &[Sample]) -> std::io::Result<String> {
+    match format.render(hist) {
+        Ok(c) => Ok(c),
+        Err(e) => {
+            tracing::error!("Failed to render {} output: {}", format, e);
+            Err(e)
+        }
+    }
+}
+
+fn write(
+    odpath: &str,
+    format: OutputFormat,
+    policy: RetryPolicy,
+    hist: &[Sample],
+) -> std::io::Result<()> {
+    let content = render(format, hist)?;
+
+    if let Err(e) = writer::write_atomic(Path::new(odpath), content.as_bytes(), policy) {
+        tracing::error!("Failed to write to log file {}: {}", odpath, e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filter::Ipv6Scope, source::FakeSource};
+    use std::net::IpAddr;
+
+    fn poller(adapters: Vec<Adapter>) -> Poller {
+        Poller::new(Box::new(FakeSource { adapters }))
+    }
+
+    fn adapter(name: &str, addresses: &[&str]) -> Adapter {
+        Adapter {
+            name: name.to_owned(),
+            addresses: addresses.iter().map(|a| a.parse().unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn poll_keeps_sorted_unique_ipv4() {
+        let mut p = poller(vec![
+            adapter("lo", &["127.0.0.1", "::1"]),
+            adapter("eth0", &["192.168.1.20", "fe80::1", "224.0.0.1"]),
+            adapter("wlan0", &["10.0.0.5", "192.168.1.20"]),
+        ]);
+        assert!(p
+            .poll(&AddressFilter::default(), &HistoryPolicy::default())
+            .unwrap());
+
+        let expected: Vec<IpAddr> =
+            vec!["10.0.0.5".parse().unwrap(), "192.168.1.20".parse().unwrap()];
+        assert_eq!(p.ip_addr_hist.len(), 1);
+        assert_eq!(p.ip_addr_hist[0].addresses(), expected);
+        assert_eq!(p.ip_addr_hist[0].adapters.len(), 2);
+    }
+
+    #[test]
+    fn poll_skips_unchanged_addresses() {
+        let filter = AddressFilter::default();
+        let history = HistoryPolicy::default();
+        let mut p = poller(vec![adapter("eth0", &["192.168.1.20"])]);
+        assert!(p.poll(&filter, &history).unwrap());
+        assert!(!p.poll(&filter, &history).unwrap());
+        assert_eq!(p.ip_addr_hist.len(), 1);
+        assert_eq!(p.changes.len(), 1);
+        assert!(p.ip_addr_hist[0].last_seen >= p.ip_addr_hist[0].timestamp);
+
+        p.source = Box::new(FakeSource {
+            adapters: vec![adapter("eth0", &["192.168.1.21"])],
+        });
+        assert!(p.poll(&filter, &history).unwrap());
+        assert_eq!(p.ip_addr_hist.len(), 2);
+        assert_eq!(p.changes.len(), 3);
+    }
+
+    #[test]
+    fn stable_addresses_with_random_iids_keep_their_scope() {
+        let stable = ["2001:db8::8d2c:4a1e:93f7:b510", "fe80::5c1a:e3ff:7d42:9b01"];
+        let mut eth0 = adapter("eth0", &stable);
+        eth0.addresses
+            .push("2001:db8::41c7:2e90:c5d3:18aa".parse().unwrap());
+        eth0.temporary = vec!["2001:db8::41c7:2e90:c5d3:18aa".parse().unwrap()];
+        let history = HistoryPolicy::default();
+
+        let mut p = poller(vec![eth0.clone()]);
+        let filter = AddressFilter {
+            ipv6: vec![Ipv6Scope::LinkLocal, Ipv6Scope::Global],
+            rules: vec![],
+        };
+        p.poll(&filter, &history).unwrap();
+        let expected: Vec<IpAddr> = stable.iter().map(|a| a.parse().unwrap()).collect();
+        assert_eq!(p.ip_addr_hist[0].addresses(), expected);
+
+        let mut p = poller(vec![eth0]);
+        let filter = AddressFilter {
+            ipv6: vec![Ipv6Scope::Temporary],
+            rules: vec![],
+        };
+        p.poll(&filter, &history).unwrap();
+        assert_eq!(p.addresses(), 1);
+    }
+
+    #[test]
+    fn poll_truncates_history() {
+        let history = HistoryPolicy {
+            depth: 3,
+            max_age: None,
+        };
+        let mut p = poller(vec![]);
+        for i in 0..history.depth + 2 {
+            p.source = Box::new(FakeSource {
+                adapters: vec![adapter("eth0", &[&format!("192.168.1.{}", i + 1)])],
+            });
+            p.poll(&AddressFilter::default(), &history).unwrap();
+        }
+        assert_eq!(p.ip_addr_hist.len(), history.depth);
+    }
+
+    #[test]
+    fn failing_output_does_not_stop_the_others() {
+        let dir = std::env::temp_dir().join(format!("ip_to_file_outputs_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let mut config = Config::resolve("svc", Default::default());
+        config.ip_log = path("missing/ip.txt");
+        config.journal = None;
+        config.write_retries = 0;
+        config.outputs = vec![Output {
+            path: path("ip.json"),
+            format: Some(OutputFormat::Json),
+            filters: Some(vec!["deny name:wlan0".parse().unwrap()]),
+            ..Default::default()
+        }];
+
+        let mut p = poller(vec![
+            adapter("eth0", &["192.168.1.20"]),
+            adapter("wlan0", &["10.0.0.5"]),
+        ]);
+        assert!(p.update(&config, false).is_err());
+        let json = std::fs::read_to_string(path("ip.json")).unwrap();
+        assert!(json.contains("192.168.1.20"));
+        assert!(!json.contains("10.0.0.5"));
+        assert_eq!(p.addresses(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_journal_appends_are_retried() {
+        let dir = std::env::temp_dir().join(format!("ip_to_file_pending_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let mut config = Config::resolve("svc", Default::default());
+        config.ip_log = path("ip.txt");
+        config.journal = Some(path("missing/journal.jsonl"));
+
+        let mut p = poller(vec![adapter("eth0", &["192.168.1.20"])]);
+        assert!(p.update(&config, false).is_err());
+        p.source = Box::new(FakeSource {
+            adapters: vec![adapter("eth0", &["192.168.1.21"])],
+        });
+        assert!(p.update(&config, false).is_err());
+        assert_eq!(p.changes.len(), 3);
+
+        config.journal = Some(path("journal.jsonl"));
+        p.update(&config, false).unwrap();
+        assert!(p.changes.is_empty());
+        let journal = std::fs::read_to_string(path("journal.jsonl")).unwrap();
+        assert_eq!(journal.lines().count(), 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
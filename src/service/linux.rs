@@ -0,0 +1,341 @@
+use super::{Control, ExitError, State};
+use crate::{
+    config::{self, ConfigLayer},
+    watch::Event,
+};
+use sd_notify::NotifyState;
+use signal_hook::{
+    consts::{SIGCONT, SIGHUP, SIGINT, SIGTERM, SIGTSTP, SIGUSR1, SIGUSR2},
+    iterator::Signals,
+};
+use std::{ffi::c_int, path::PathBuf, process::Command, sync::mpsc, time::Duration};
+
+pub use std::io::{Error, Result};
+
+const UNIT_DIR: &str = "/etc/systemd/system";
+
+pub fn other_error<E>(e: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    Error::other(e)
+}
+
+pub fn run(service_name: &str) -> Result<()> {
+    run_console(service_name, &ConfigLayer::default(), None)
+}
+
+/// systemd runs the service in the foreground already, so the console is the
+/// same loop; the readiness notifications do nothing outside systemd.
+/// Settings that can't be loaded and too many failed polls are returned as
+/// an `ExitError`.
+pub fn run_console(service_name: &str, cli: &ConfigLayer, config_path: Option<&str>) -> Result<()> {
+    tracing::info!("Running service: {}", service_name);
+
+    let (shutdown_tx, shutdown_rx) = mpsc::channel();
+
+    let config = config::load(service_name, cli, config_path)
+        .map_err(|e| exit_error(super::EXIT_INVALID_CONFIG, e))?;
+
+    let mut signals = Signals::new([
+        SIGTERM,
+        SIGINT,
+        SIGHUP,
+        SIGTSTP,
+        SIGCONT,
+        SIGUSR1,
+        SIGUSR2,
+        diagnostics_signal(),
+    ])?;
+    let signal_tx = shutdown_tx.clone();
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            let event = match signal {
+                SIGHUP => {
+                    tracing::info!("Received SIGHUP, reloading settings");
+                    Event::Reload
+                }
+                SIGTSTP => {
+                    tracing::info!("Received SIGTSTP, pausing");
+                    Event::Pause
+                }
+                SIGCONT => Event::Continue,
+                SIGUSR1 => {
+                    tracing::info!("Received SIGUSR1, rotating logs");
+                    Event::RotateLogs
+                }
+                SIGUSR2 => {
+                    tracing::info!("Received SIGUSR2, polling");
+                    Event::Refresh
+                }
+                signal if signal == diagnostics_signal() => {
+                    tracing::info!("Received SIGRTMIN, logging diagnostics");
+                    Event::Diagnostics
+                }
+                SIGTERM if system_stopping() => {
+                    tracing::info!("Received SIGTERM, the system is shutting down");
+                    Event::SystemShutdown
+                }
+                _ => {
+                    tracing::info!("Received signal {}, shutting down", signal);
+                    Event::Shutdown
+                }
+            };
+            if let Err(e) = signal_tx.send(event) {
+                tracing::error!("Failed to send {:?} to the service loop: {}", event, e);
+                break;
+            }
+            if matches!(event, Event::Shutdown | Event::SystemShutdown) {
+                break;
+            }
+        }
+    });
+
+    // systemd has no paused state.
+    let mut report = |state| match state {
+        State::Running => {
+            if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+                tracing::error!("Failed to notify systemd of readiness: {}", e);
+            }
+        }
+        State::Stopping => {
+            if let Err(e) = sd_notify::notify(false, &[NotifyState::Stopping]) {
+                tracing::error!("Failed to notify systemd of shutdown: {}", e);
+            }
+        }
+        _ => (),
+    };
+
+    super::run_loop(
+        service_name,
+        config,
+        cli,
+        config_path,
+        shutdown_tx,
+        shutdown_rx,
+        &mut report,
+    )
+    .map_err(|e| exit_error(super::EXIT_TOO_MANY_FAILURES, e))
+}
+
+/// `error`, ending the process with `code`.
+pub fn exit_error(code: u32, error: Error) -> Error {
+    ExitError::wrap(code, error)
+}
+
+/// The exit code `error` asks for, if any.
+pub fn exit_code(error: &Error) -> Option<u32> {
+    ExitError::code(error)
+}
+
+fn unit_path(service_name: &str) -> PathBuf {
+    PathBuf::from(UNIT_DIR).join(format!("{}.service", service_name))
+}
+
+/// SIGRTMIN isn't a constant with glibc.
+fn diagnostics_signal() -> c_int {
+    nix::libc::SIGRTMIN()
+}
+
+/// The signal each `Control` is sent as, matching the signal thread in
+/// `run_console`.
+fn control_signal(control: Control) -> c_int {
+    match control {
+        Control::Poll => SIGUSR2,
+        Control::Reload => SIGHUP,
+        Control::Shutdown => SIGTERM,
+        Control::RotateLogs => SIGUSR1,
+        Control::Diagnostics => diagnostics_signal(),
+    }
+}
+
+/// Sends `control` to the running service as a signal through systemd.
+pub fn send_control(service_name: &str, control: Control) -> Result<()> {
+    if state(service_name)?.0 != State::Running {
+        tracing::error!("{} is not running", service_name);
+        return Err(other_error(format!("{} is not running", service_name)));
+    }
+    tracing::info!("Sending {:?} to {}", control, service_name);
+    systemctl(&[
+        "kill",
+        "--kill-who=main",
+        &format!("--signal={}", control_signal(control)),
+        service_name,
+    ])
+}
+
+/// Whether systemd is taking the whole system down rather than stopping just
+/// this service.
+fn system_stopping() -> bool {
+    Command::new("systemctl")
+        .arg("is-system-running")
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).trim() == "stopping")
+}
+
+fn systemctl(args: &[&str]) -> Result<()> {
+    tracing::info!("systemctl {}", args.join(" "));
+    let status = Command::new("systemctl").args(args).status()?;
+    if !status.success() {
+        tracing::error!("systemctl {} failed: {}", args.join(" "), status);
+        return Err(Error::other(format!(
+            "systemctl {} failed: {}",
+            args.join(" "),
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// `systemctl show` for the unit's load and active state and main PID.
+pub fn state(service_name: &str) -> Result<(State, Option<u32>)> {
+    let output = Command::new("systemctl")
+        .args([
+            "show",
+            "--property=LoadState,ActiveState,MainPID",
+            service_name,
+        ])
+        .output()?;
+    if !output.status.success() {
+        tracing::error!("systemctl show {} failed: {}", service_name, output.status);
+        return Err(Error::other(format!(
+            "systemctl show {} failed: {}",
+            service_name, output.status
+        )));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let property = |name: &str| {
+        stdout
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .unwrap_or_default()
+    };
+    if property("LoadState") == "not-found" {
+        return Ok((State::NotInstalled, None));
+    }
+    let state = match property("ActiveState") {
+        "active" | "reloading" => State::Running,
+        "activating" => State::Starting,
+        "deactivating" => State::Stopping,
+        "failed" => State::Failed,
+        _ => State::Stopped,
+    };
+    let pid = property("MainPID").parse().ok().filter(|pid| *pid != 0);
+    Ok((state, pid))
+}
+
+pub fn install_service(
+    service_exe_name: &str,
+    service_name: &str,
+    display_name: &str,
+    description: &str,
+) -> Result<()> {
+    let service_binary_path = match std::env::current_exe() {
+        Ok(path) => path,
+        Err(e) => {
+            tracing::error!("Failed to get current executable path: {}", e);
+            return Err(e);
+        }
+    };
+    let service_binary_path = service_binary_path.with_file_name(service_exe_name);
+
+    tracing::info!("Service Binary: {}", service_binary_path.display());
+
+    let unit = format!(
+        "# {description}\n\
+         [Unit]\n\
+         Description={display_name}\n\
+         After=network-online.target\n\
+         Wants=network-online.target\n\
+         \n\
+         [Service]\n\
+         Type=notify\n\
+         ExecStart={exe}\n\
+         ExecReload=/bin/kill -HUP $MAINPID\n\
+         Restart=on-failure\n\
+         RestartPreventExitStatus={too_many_failures} {invalid_config}\n\
+         StateDirectory={service_name}\n\
+         WorkingDirectory=/var/lib/{service_name}\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        exe = service_binary_path.display(),
+        too_many_failures = super::EXIT_TOO_MANY_FAILURES,
+        invalid_config = super::EXIT_INVALID_CONFIG,
+    );
+
+    let path = unit_path(service_name);
+    tracing::info!("Create Service unit {}", path.display());
+    if let Err(e) = std::fs::write(&path, unit) {
+        tracing::error!("Failed to write unit file {}: {}", path.display(), e);
+        return Err(e);
+    }
+    systemctl(&["daemon-reload"])?;
+    // Starts the service at boot, like `systemctl enable`; `start` starts it now.
+    systemctl(&["enable", service_name])?;
+
+    tracing::info!("Service Install complete");
+
+    Ok(())
+}
+
+/// Stopping and disabling are best effort: a unit that won't stop in time or
+/// was never enabled is removed all the same.
+pub fn uninstall_service(service_name: &str) -> Result<()> {
+    if let Err(e) = stop_service(
+        service_name,
+        Duration::from_secs(super::DEFAULT_TIMEOUT_SECS),
+    ) {
+        tracing::warn!("Failed to stop the service, removing it anyway: {}", e);
+    }
+    if let Err(e) = systemctl(&["disable", service_name]) {
+        tracing::warn!("Failed to disable the service, removing it anyway: {}", e);
+    }
+
+    let path = unit_path(service_name);
+    tracing::info!("Delete service unit {}", path.display());
+    let removed = std::fs::remove_file(&path);
+    if let Err(e) = &removed {
+        tracing::error!("Failed to remove unit file {}: {}", path.display(), e);
+    }
+    systemctl(&["daemon-reload"])?;
+    removed?;
+
+    tracing::info!("Uninstalled service");
+    Ok(())
+}
+
+pub fn start_service(service_name: &str, timeout: Duration) -> Result<()> {
+    match state(service_name)?.0 {
+        State::Running => {
+            tracing::info!("Service already running");
+            return Ok(());
+        }
+        State::Stopping => super::wait_for_state(service_name, State::Stopped, timeout)?,
+        _ => (),
+    }
+    tracing::info!("Start service");
+    systemctl(&["start", "--no-block", service_name])?;
+    super::wait_for_state(service_name, State::Running, timeout)?;
+    tracing::info!("Service running");
+    Ok(())
+}
+
+pub fn stop_service(service_name: &str, timeout: Duration) -> Result<()> {
+    if matches!(state(service_name)?.0, State::Stopped | State::Failed) {
+        tracing::info!("Service already stopped");
+        return Ok(());
+    }
+    tracing::info!("Stop service");
+    systemctl(&["stop", "--no-block", service_name])?;
+    super::wait_for_state(service_name, State::Stopped, timeout)?;
+    tracing::info!("Service stopped");
+    Ok(())
+}
+
+pub fn restart_service(service_name: &str, timeout: Duration) -> Result<()> {
+    tracing::info!("Restart service");
+    stop_service(service_name, timeout)?;
+    start_service(service_name, timeout)
+}
@@ -0,0 +1,403 @@
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub use windows::*;
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+pub use linux::*;
+
+use crate::{
+    config::{self, Config, ConfigLayer},
+    health::Health,
+    http::{HttpServer, Snapshot},
+    journal,
+    poller::Poller,
+    utils,
+    watch::{self, Event, Wake, Watchers},
+};
+use std::{
+    path::Path,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// How long start, stop and restart wait for the service by default.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Service specific exit codes, the process exit code on Linux.
+/// The settings could not be loaded (EX_CONFIG).
+pub const EXIT_INVALID_CONFIG: u32 = 78;
+/// `max_failures` polls in a row failed (EX_TEMPFAIL).
+pub const EXIT_TOO_MANY_FAILURES: u32 = 75;
+
+/// An error that ends the service with one of the exit codes above. The
+/// backends return it for `main` to exit with, after everything is dropped.
+#[derive(Debug)]
+pub struct ExitError {
+    pub code: u32,
+    pub error: std::io::Error,
+}
+
+impl ExitError {
+    pub fn wrap(code: u32, error: std::io::Error) -> std::io::Error {
+        std::io::Error::new(error.kind(), ExitError { code, error })
+    }
+
+    /// The exit code `error` asks for, if it is an `ExitError`.
+    pub fn code(error: &std::io::Error) -> Option<u32> {
+        error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<ExitError>())
+            .map(|e| e.code)
+    }
+}
+
+impl std::fmt::Display for ExitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for ExitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// The polling loop shared by the service backends and console mode. Runs
+/// until `rx` delivers `Event::Shutdown`; `tx` is handed to the watchers.
+/// `report` is told when the loop is running, paused and stopping, for the
+/// backend to pass on to the service manager. On `Event::SystemShutdown` it
+/// writes every output once more before it stops.
+/// Settings are reloaded from `cli` and `config_path` whenever it wakes.
+/// A failed poll is retried with backoff and only ends the loop, with the
+/// last error, after `max_failures` failures in a row. With `http` set, the
+/// history and health record are served from a snapshot taken after each poll.
+pub fn run_loop(
+    service_name: &str,
+    mut config: Config,
+    cli: &ConfigLayer,
+    config_path: Option<&str>,
+    tx: Sender<Event>,
+    rx: Receiver<Event>,
+    report: &mut dyn FnMut(State),
+) -> std::io::Result<()> {
+    let mut watchers = Watchers::new(tx.clone());
+    watchers.update(&config);
+    #[cfg(windows)]
+    let _registry =
+        watch::RegistryWatcher::start(crate::store::service_key(service_name), tx.clone());
+
+    let mut poller = Poller::default();
+    let mut health = Health::new();
+    let snapshot = Arc::new(RwLock::new(Snapshot::default()));
+    let mut http = serve(&config, &snapshot);
+    // Set after a reload so new output settings apply without an address change.
+    let mut reloaded = false;
+    let mut refresh = false;
+
+    report(State::Running);
+    loop {
+        // After a failure the files may be behind, even if the addresses
+        // are unchanged.
+        let force_write = reloaded || refresh || health.consecutive_failures > 0;
+        let result = poll(&mut poller, &mut health, &config, force_write);
+        if http.is_some() {
+            publish(&snapshot, &poller, &health);
+        }
+
+        let delay = match result {
+            Ok(_) => config.poll_rate(),
+            Err(e) => {
+                let failures = health.consecutive_failures;
+                if config.max_failures > 0 && failures >= config.max_failures {
+                    tracing::error!("Stopping after {} failed polls in a row", failures);
+                    report(State::Stopping);
+                    return Err(e);
+                }
+                let delay = config.retry_delay(failures);
+                tracing::warn!(
+                    "Poll failed ({} in a row), retrying in {:?}",
+                    failures,
+                    delay
+                );
+                delay
+            }
+        };
+
+        let mut on_demand = |event| match event {
+            Event::RotateLogs => rotate_logs(&config),
+            Event::Diagnostics => diagnostics(&config, &health),
+            _ => (),
+        };
+        let mut wake = watch::wait(&rx, delay, config.debounce(), &mut on_demand);
+        if wake == Wake::Pause {
+            tracing::info!("Paused");
+            report(State::Paused);
+            wake = watch::paused(&rx, &mut on_demand);
+            if wake == Wake::Poll {
+                tracing::info!("Continuing");
+                report(State::Running);
+            }
+        }
+        match wake {
+            Wake::Poll | Wake::Pause => (),
+            Wake::Refresh => tracing::info!("Polling on request"),
+            Wake::Shutdown => {
+                report(State::Stopping);
+                break;
+            }
+            Wake::SystemShutdown => {
+                tracing::info!("System shutting down, writing the outputs once more");
+                report(State::Stopping);
+                // Logged by `update`; there is no retrying at this point.
+                let _ = poll(&mut poller, &mut health, &config, true);
+                break;
+            }
+        };
+
+        refresh = wake == Wake::Refresh;
+        reloaded = match config::reload(service_name, cli, config_path, &config) {
+            Some(new) => {
+                if new.log != config.log {
+                    if let Err(e) = utils::set_log_file(&new.log) {
+                        tracing::error!("Failed to switch log file to {}: {}", new.log, e);
+                    }
+                }
+                if new.http != config.http {
+                    // The old listener has to let go of its port first.
+                    drop(http.take());
+                    http = serve(&new, &snapshot);
+                    if http.is_some() {
+                        publish(&snapshot, &poller, &health);
+                    }
+                }
+                config = new;
+                watchers.update(&config);
+                true
+            }
+            None => false,
+        };
+    }
+
+    Ok(())
+}
+
+/// Starts the HTTP endpoint if one is configured. Failing to bind is logged
+/// by `start` and leaves the service running without it.
+fn serve(config: &Config, snapshot: &Arc<RwLock<Snapshot>>) -> Option<HttpServer> {
+    let address = config.http.as_deref()?;
+    HttpServer::start(address, snapshot.clone()).ok()
+}
+
+/// Replaces what the HTTP endpoint serves.
+fn publish(snapshot: &RwLock<Snapshot>, poller: &Poller, health: &Health) {
+    match snapshot.write() {
+        Ok(mut snapshot) => {
+            *snapshot = Snapshot {
+                history: poller.history().to_vec(),
+                health: Some(health.clone()),
+            }
+        }
+        Err(e) => tracing::error!("Failed to update the HTTP snapshot: {}", e),
+    }
+}
+
+/// Reopens the log file, e.g. after logrotate moved it, and starts a new
+/// journal.
+fn rotate_logs(config: &Config) {
+    tracing::info!("Rotating logs on request");
+    if let Err(e) = utils::set_log_file(&config.log) {
+        tracing::error!("Failed to reopen log file {}: {}", config.log, e);
+    }
+    if let Some(path) = &config.journal {
+        if let Err(e) = journal::rotate(Path::new(path)) {
+            tracing::error!("Failed to rotate journal {}: {}", path, e);
+        }
+    }
+}
+
+/// Logs what `status` shows and the settings in effect.
+fn diagnostics(config: &Config, health: &Health) {
+    match serde_json::to_string(health) {
+        Ok(health) => tracing::info!("Diagnostics: health {}", health),
+        Err(e) => tracing::error!("Failed to serialize the health record: {}", e),
+    }
+    for (key, value) in ConfigLayer::from(config).entries() {
+        tracing::info!("Diagnostics: {} = {}", key, value);
+    }
+    for file in &config.files {
+        tracing::info!("Diagnostics: settings file {}", file.display());
+    }
+}
+
+/// One pass of the loop, recorded in the health record.
+fn poll(
+    poller: &mut Poller,
+    health: &mut Health,
+    config: &Config,
+    force_write: bool,
+) -> std::io::Result<bool> {
+    let result = poller.update(config, force_write);
+    health.record(&result, poller.addresses());
+    if let Some(path) = &config.health {
+        // Logged by `save`; the service runs on without a health record.
+        let _ = health.save(Path::new(path));
+    }
+    result
+}
+
+/// One poll without the service: writes the IP file, or returns its content
+/// if `print` is set. The history starts empty, so the file holds only the
+/// current addresses, and nothing is journaled.
+pub fn run_once(config: &Config, print: bool) -> std::io::Result<Option<String>> {
+    let mut poller = Poller::default();
+    if print {
+        poller.poll(&config.address_filter(), &config.history_policy())?;
+        return poller.render(config.ip_log_format).map(Some);
+    }
+    let config = Config {
+        journal: None,
+        ..config.clone()
+    };
+    poller.update(&config, true)?;
+    Ok(None)
+}
+
+/// On-demand actions a running service takes when told to, with a user
+/// control code on Windows or a signal on Linux.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Control {
+    /// Poll now and rewrite every output
+    Poll,
+    /// Load the settings again
+    Reload,
+    /// Stop the service
+    Shutdown,
+    /// Reopen the log file and rotate the journal
+    RotateLogs,
+    /// Log the health counters and the settings in effect
+    Diagnostics,
+}
+
+impl Control {
+    /// The Windows user control code (128 to 255); 130 has always stopped
+    /// the service.
+    #[cfg(windows)]
+    pub fn code(self) -> u32 {
+        match self {
+            Control::Poll => 128,
+            Control::Reload => 129,
+            Control::Shutdown => 130,
+            Control::RotateLogs => 131,
+            Control::Diagnostics => 132,
+        }
+    }
+
+    #[cfg(windows)]
+    pub fn from_code(code: u32) -> Option<Control> {
+        <Control as clap::ValueEnum>::value_variants()
+            .iter()
+            .copied()
+            .find(|control| control.code() == code)
+    }
+
+    /// The event the service loop is sent.
+    #[cfg(windows)]
+    pub fn event(self) -> Event {
+        match self {
+            Control::Poll => Event::Refresh,
+            Control::Reload => Event::Reload,
+            Control::Shutdown => Event::Shutdown,
+            Control::RotateLogs => Event::RotateLogs,
+            Control::Diagnostics => Event::Diagnostics,
+        }
+    }
+}
+
+/// The service as the service manager sees it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    NotInstalled,
+    Stopped,
+    Starting,
+    Running,
+    Stopping,
+    #[cfg(windows)]
+    Pausing,
+    /// Polling suspended; systemd has no such state, so only the loop reports
+    /// it there.
+    Paused,
+    #[cfg(windows)]
+    Resuming,
+    /// Stopped after a failure, as systemd reports it.
+    #[cfg(target_os = "linux")]
+    Failed,
+}
+
+impl std::fmt::Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            State::NotInstalled => "not installed",
+            State::Stopped => "stopped",
+            State::Starting => "starting",
+            State::Running => "running",
+            State::Stopping => "stopping",
+            #[cfg(windows)]
+            State::Pausing => "pausing",
+            State::Paused => "paused",
+            #[cfg(windows)]
+            State::Resuming => "resuming",
+            #[cfg(target_os = "linux")]
+            State::Failed => "failed",
+        })
+    }
+}
+
+/// Polls the service manager until the service is in `target`. Fails after
+/// `timeout`, or at once if the service can't get there any more.
+pub fn wait_for_state(service_name: &str, target: State, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let (state, _) = state(service_name)?;
+        match state {
+            s if s == target => return Ok(()),
+            State::NotInstalled => {
+                tracing::error!("{} is not installed", service_name);
+                return Err(other_error(format!("{} is not installed", service_name)));
+            }
+            #[cfg(target_os = "linux")]
+            State::Failed if target == State::Stopped => return Ok(()),
+            #[cfg(target_os = "linux")]
+            State::Failed => {
+                tracing::error!(
+                    "{} failed, see journalctl -u {}",
+                    service_name,
+                    service_name
+                );
+                return Err(other_error(format!("{} failed", service_name)));
+            }
+            _ => (),
+        }
+        if Instant::now() >= deadline {
+            tracing::error!(
+                "Timed out after {}s waiting for {} to be {}, it is {}",
+                timeout.as_secs(),
+                service_name,
+                target,
+                state
+            );
+            return Err(other_error(format!(
+                "timed out waiting for {} to be {}",
+                service_name, target
+            )));
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,460 @@
+use super::{Control, ExitError, State};
+use crate::{
+    config::{self, ConfigLayer},
+    watch::Event,
+};
+use std::{
+    ffi::{OsStr, OsString},
+    sync::{mpsc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType, UserEventCode,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+use windows_sys::Win32::Foundation::ERROR_SERVICE_DOES_NOT_EXIST;
+
+pub use windows_service::{Error, Result};
+
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+static SERVICE_NAME: LazyLock<Mutex<String>> = LazyLock::new(|| Mutex::new(String::default()));
+
+pub fn other_error<E>(e: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    Error::Winapi(std::io::Error::other(e))
+}
+
+pub fn run(service_name: &str) -> Result<()> {
+    tracing::info!("Running service: {}", service_name);
+    {
+        let mut lock = match SERVICE_NAME.lock() {
+            Ok(l) => l,
+            Err(e) => {
+                tracing::error!("Failed to lock SERVICE_NAME in 'run': {}", e);
+                return Err(windows_service::Error::Winapi(std::io::Error::other(
+                    e.to_string(),
+                )));
+            }
+        };
+        *lock = service_name.to_owned();
+    }
+
+    service_dispatcher::start(service_name, ffi_service_main)
+}
+
+define_windows_service!(ffi_service_main, my_service_main);
+fn my_service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        tracing::error!("Run service failed: {:#?}", e)
+    }
+}
+
+fn run_service() -> Result<()> {
+    let (shutdown_tx, shutdown_rx) = mpsc::channel();
+    let watch_tx = shutdown_tx.clone();
+
+    let event_handler = move |control_event| -> ServiceControlHandlerResult {
+        match control_event {
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            ServiceControl::Stop => forward(&shutdown_tx, Event::Shutdown, 1),
+            ServiceControl::ParamChange => {
+                tracing::info!("Received parameter change, reloading settings");
+                forward(&shutdown_tx, Event::Reload, 3)
+            }
+            ServiceControl::Pause => forward(&shutdown_tx, Event::Pause, 4),
+            ServiceControl::Continue => forward(&shutdown_tx, Event::Continue, 5),
+            ServiceControl::Preshutdown | ServiceControl::Shutdown => {
+                forward(&shutdown_tx, Event::SystemShutdown, 6)
+            }
+            ServiceControl::UserEvent(code) => match Control::from_code(code.to_raw()) {
+                Some(control) => {
+                    tracing::info!("Received control code {}, {:?}", code.to_raw(), control);
+                    forward(&shutdown_tx, control.event(), 2)
+                }
+                None => {
+                    tracing::warn!("Ignoring unknown control code {}", code.to_raw());
+                    ServiceControlHandlerResult::NotImplemented
+                }
+            },
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+    let service_name;
+    {
+        let lock = match SERVICE_NAME.lock() {
+            Ok(l) => l,
+            Err(e) => {
+                tracing::error!("Failed to lock SERVICE_NAME in 'run_service': {}", e);
+                return Err(windows_service::Error::Winapi(std::io::Error::other(
+                    e.to_string(),
+                )));
+            }
+        };
+        service_name = lock.clone();
+    }
+    let status_handle = service_control_handler::register(&service_name, event_handler)?;
+    let mut status = Status {
+        handle: status_handle,
+        checkpoint: 0,
+    };
+    status.set(ServiceState::StartPending, ServiceExitCode::Win32(0))?;
+
+    // Errors are logged where they happen; the exit code tells the SCM why
+    // the service stopped instead of leaving it marked as running.
+    let exit_code = match config::load(&service_name, &ConfigLayer::default(), None) {
+        Ok(config) => {
+            status.set(ServiceState::StartPending, ServiceExitCode::Win32(0))?;
+            let mut report = |state| {
+                let state = match state {
+                    State::Running => ServiceState::Running,
+                    State::Paused => ServiceState::Paused,
+                    State::Stopping => ServiceState::StopPending,
+                    _ => return,
+                };
+                if let Err(e) = status.set(state, ServiceExitCode::Win32(0)) {
+                    tracing::error!("Failed to report {:?} to the SCM: {}", state, e);
+                }
+            };
+            match super::run_loop(
+                &service_name,
+                config,
+                &ConfigLayer::default(),
+                None,
+                watch_tx,
+                shutdown_rx,
+                &mut report,
+            ) {
+                Ok(()) => ServiceExitCode::Win32(0),
+                Err(_) => ServiceExitCode::ServiceSpecific(super::EXIT_TOO_MANY_FAILURES),
+            }
+        }
+        Err(_) => ServiceExitCode::ServiceSpecific(super::EXIT_INVALID_CONFIG),
+    };
+
+    // Tell the system that service has stopped.
+    status.set(ServiceState::Stopped, exit_code)?;
+
+    Ok(())
+}
+
+/// Passes a control on to the service loop; `code` tells the SCM which
+/// control could not be handled.
+fn forward(tx: &mpsc::Sender<Event>, event: Event, code: u32) -> ServiceControlHandlerResult {
+    if let Err(e) = tx.send(event) {
+        tracing::error!("Failed to send {:?} to the service loop: {}", event, e);
+        return ServiceControlHandlerResult::Other(code);
+    }
+    ServiceControlHandlerResult::NoError
+}
+
+/// How long the SCM should wait for the next checkpoint of a pending state.
+const PENDING_WAIT_HINT: Duration = Duration::from_secs(10);
+
+/// The status reported to the SCM. The checkpoint counts up while a state is
+/// pending, so the SCM can tell a slow start or stop from a hung one.
+struct Status {
+    handle: ServiceStatusHandle,
+    checkpoint: u32,
+}
+
+impl Status {
+    fn set(&mut self, state: ServiceState, exit_code: ServiceExitCode) -> Result<()> {
+        let pending = matches!(
+            state,
+            ServiceState::StartPending
+                | ServiceState::StopPending
+                | ServiceState::PausePending
+                | ServiceState::ContinuePending
+        );
+        self.checkpoint = if pending { self.checkpoint + 1 } else { 0 };
+        let controls_accepted = match state {
+            ServiceState::Running | ServiceState::Paused => {
+                ServiceControlAccept::STOP
+                    | ServiceControlAccept::PAUSE_CONTINUE
+                    | ServiceControlAccept::PARAM_CHANGE
+                    | ServiceControlAccept::SHUTDOWN
+                    | ServiceControlAccept::PRESHUTDOWN
+            }
+            _ => ServiceControlAccept::empty(),
+        };
+        self.handle.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state: state,
+            controls_accepted,
+            exit_code,
+            checkpoint: self.checkpoint,
+            wait_hint: if pending {
+                PENDING_WAIT_HINT
+            } else {
+                Duration::default()
+            },
+            process_id: None,
+        })
+    }
+}
+
+/// Sender for `console_ctrl`, set while the loop runs in the console.
+static CONSOLE_TX: Mutex<Option<mpsc::Sender<Event>>> = Mutex::new(None);
+
+/// Runs the service loop in the console, outside the service control
+/// manager, until Ctrl+C.
+pub fn run_console(service_name: &str, cli: &ConfigLayer, config_path: Option<&str>) -> Result<()> {
+    use windows_sys::Win32::System::Console::SetConsoleCtrlHandler;
+
+    tracing::info!("Running in the console: {}", service_name);
+
+    let (shutdown_tx, shutdown_rx) = mpsc::channel();
+    match CONSOLE_TX.lock() {
+        Ok(mut lock) => *lock = Some(shutdown_tx.clone()),
+        Err(e) => {
+            tracing::error!("Failed to lock CONSOLE_TX in 'run_console': {}", e);
+            return Err(windows_service::Error::Winapi(std::io::Error::other(
+                e.to_string(),
+            )));
+        }
+    }
+    // SAFETY: `console_ctrl` is a valid handler for the life of the process.
+    if unsafe { SetConsoleCtrlHandler(Some(console_ctrl), 1) } == 0 {
+        let e = std::io::Error::last_os_error();
+        tracing::error!("Failed to set the Ctrl+C handler: {}", e);
+        return Err(windows_service::Error::Winapi(e));
+    }
+
+    let config = config::load(service_name, cli, config_path)
+        .map_err(|e| exit_error(super::EXIT_INVALID_CONFIG, e))?;
+    super::run_loop(
+        service_name,
+        config,
+        cli,
+        config_path,
+        shutdown_tx,
+        shutdown_rx,
+        &mut |_| (),
+    )
+    .map_err(|e| {
+        exit_error(
+            super::EXIT_TOO_MANY_FAILURES,
+            windows_service::Error::Winapi(e),
+        )
+    })
+}
+
+/// `error`, ending the process with `code`. Only I/O errors carry one.
+pub fn exit_error(code: u32, error: Error) -> Error {
+    match error {
+        windows_service::Error::Winapi(e) => {
+            windows_service::Error::Winapi(ExitError::wrap(code, e))
+        }
+        e => e,
+    }
+}
+
+/// The exit code `error` asks for, if any; the service itself reports its
+/// exit code to the SCM instead.
+pub fn exit_code(error: &Error) -> Option<u32> {
+    match error {
+        windows_service::Error::Winapi(e) => ExitError::code(e),
+        _ => None,
+    }
+}
+
+/// Ctrl+C, Ctrl+Break and closing the console all stop the loop.
+unsafe extern "system" fn console_ctrl(_ctrl_type: u32) -> windows_sys::core::BOOL {
+    if let Ok(lock) = CONSOLE_TX.lock() {
+        if let Some(tx) = lock.as_ref() {
+            tracing::info!("Received Ctrl+C, shutting down");
+            let _ = tx.send(Event::Shutdown);
+        }
+    }
+    1
+}
+
+pub fn install_service(
+    service_exe_name: &str,
+    service_name: &str,
+    display_name: &str,
+    description: &str,
+) -> windows_service::Result<()> {
+    let service_binary_path = match std::env::current_exe() {
+        Ok(path) => path,
+        Err(e) => {
+            tracing::error!("Failed to get current executable path: {}", e);
+            return Err(windows_service::Error::Winapi(std::io::Error::other(e)));
+        }
+    };
+    let service_binary_path = service_binary_path.with_file_name(service_exe_name);
+
+    tracing::info!("Service Binary: {}", service_binary_path.display());
+
+    tracing::info!("Connecting to Service Manager");
+    let manager_access = ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE;
+    let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
+
+    let service_info = ServiceInfo {
+        name: OsString::from(service_name),
+        display_name: OsString::from(display_name),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::OnDemand,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: service_binary_path,
+        launch_arguments: vec![],
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+
+    tracing::info!("Create Service");
+    let service = service_manager.create_service(&service_info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description(description)?;
+
+    tracing::info!("Service Install complete");
+
+    Ok(())
+}
+
+pub fn uninstall_service(service_name: &str) -> windows_service::Result<()> {
+    tracing::info!("Connecting to Service Manager");
+    let manager_access = ServiceManagerAccess::CONNECT;
+    let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
+
+    let service_access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE;
+    let service = service_manager.open_service(service_name, service_access)?;
+
+    tracing::info!("Delete service");
+    service.delete()?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    drop(service);
+
+    tracing::info!("Wait for service deletion");
+
+    let start = Instant::now();
+    let timeout = Duration::from_secs(5);
+    while start.elapsed() < timeout {
+        if let Err(windows_service::Error::Winapi(e)) =
+            service_manager.open_service(service_name, ServiceAccess::QUERY_STATUS)
+        {
+            if e.raw_os_error() == Some(ERROR_SERVICE_DOES_NOT_EXIST as i32) {
+                return Ok(());
+            }
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+
+    tracing::info!("Uninstalled service");
+    Ok(())
+}
+
+pub fn state(service_name: &str) -> windows_service::Result<(State, Option<u32>)> {
+    let manager_access = ServiceManagerAccess::CONNECT;
+    let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
+
+    let service = match service_manager.open_service(service_name, ServiceAccess::QUERY_STATUS) {
+        Ok(service) => service,
+        Err(windows_service::Error::Winapi(e))
+            if e.raw_os_error() == Some(ERROR_SERVICE_DOES_NOT_EXIST as i32) =>
+        {
+            return Ok((State::NotInstalled, None));
+        }
+        Err(e) => return Err(e),
+    };
+    let status = service.query_status()?;
+    let state = match status.current_state {
+        ServiceState::Stopped => State::Stopped,
+        ServiceState::StartPending => State::Starting,
+        ServiceState::StopPending => State::Stopping,
+        ServiceState::Running => State::Running,
+        ServiceState::ContinuePending => State::Resuming,
+        ServiceState::PausePending => State::Pausing,
+        ServiceState::Paused => State::Paused,
+    };
+    Ok((state, status.process_id))
+}
+
+pub fn start_service(service_name: &str, timeout: Duration) -> windows_service::Result<()> {
+    tracing::info!("Connecting to Service Manager");
+    let manager_access = ServiceManagerAccess::CONNECT;
+    let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
+
+    let service_access =
+        ServiceAccess::QUERY_STATUS | ServiceAccess::START | ServiceAccess::PAUSE_CONTINUE;
+    let service = service_manager.open_service(service_name, service_access)?;
+
+    match service.query_status()?.current_state {
+        ServiceState::Running => {
+            tracing::info!("Service already running");
+            return Ok(());
+        }
+        ServiceState::StartPending | ServiceState::ContinuePending => (),
+        ServiceState::Paused | ServiceState::PausePending => {
+            tracing::info!("Continue service");
+            service.resume()?;
+        }
+        ServiceState::StopPending => {
+            super::wait_for_state(service_name, State::Stopped, timeout)?;
+            tracing::info!("Start service");
+            service.start::<&OsStr>(&[])?;
+        }
+        ServiceState::Stopped => {
+            tracing::info!("Start service");
+            service.start::<&OsStr>(&[])?;
+        }
+    }
+    super::wait_for_state(service_name, State::Running, timeout)?;
+    tracing::info!("Service running");
+    Ok(())
+}
+
+pub fn stop_service(service_name: &str, timeout: Duration) -> windows_service::Result<()> {
+    tracing::info!("Connecting to Service Manager");
+    let manager_access = ServiceManagerAccess::CONNECT;
+    let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
+
+    let service_access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP;
+    let service = service_manager.open_service(service_name, service_access)?;
+
+    match service.query_status()?.current_state {
+        ServiceState::Stopped => {
+            tracing::info!("Service already stopped");
+            return Ok(());
+        }
+        ServiceState::StopPending => (),
+        _ => {
+            tracing::info!("Stop service");
+            service.stop()?;
+        }
+    }
+    super::wait_for_state(service_name, State::Stopped, timeout)?;
+    tracing::info!("Service stopped");
+    Ok(())
+}
+
+/// Sends `control` to the running service as its user control code.
+pub fn send_control(service_name: &str, control: Control) -> windows_service::Result<()> {
+    let manager_access = ServiceManagerAccess::CONNECT;
+    let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
+
+    let service_access = ServiceAccess::QUERY_STATUS | ServiceAccess::USER_DEFINED_CONTROL;
+    let service = service_manager.open_service(service_name, service_access)?;
+
+    tracing::info!("Sending {:?} to {}", control, service_name);
+    let code = UserEventCode::from_raw(control.code()).map_err(other_error)?;
+    service.notify(code)?;
+    Ok(())
+}
+
+pub fn restart_service(service_name: &str, timeout: Duration) -> windows_service::Result<()> {
+    tracing::info!("Restart service");
+    stop_service(service_name, timeout)?;
+    start_service(service_name, timeout)
+}
@@ -0,0 +1,386 @@
+use serde::Serialize;
+#[cfg(target_os = "linux")]
+use std::net::Ipv6Addr;
+use std::{fmt, net::IpAddr};
+
+/// Broad link type of an adapter, used by `type:` filter rules.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdapterKind {
+    Ethernet,
+    Wireless,
+    Loopback,
+    Tunnel,
+    Ppp,
+    #[default]
+    Other,
+}
+
+impl AdapterKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdapterKind::Ethernet => "ethernet",
+            AdapterKind::Wireless => "wireless",
+            AdapterKind::Loopback => "loopback",
+            AdapterKind::Tunnel => "tunnel",
+            AdapterKind::Ppp => "ppp",
+            AdapterKind::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for AdapterKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Operational state of an adapter (RFC 2863 `ifOperStatus`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OperStatus {
+    Up,
+    Down,
+    Testing,
+    #[default]
+    Unknown,
+    Dormant,
+    NotPresent,
+    LowerLayerDown,
+}
+
+impl OperStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OperStatus::Up => "up",
+            OperStatus::Down => "down",
+            OperStatus::Testing => "testing",
+            OperStatus::Unknown => "unknown",
+            OperStatus::Dormant => "dormant",
+            OperStatus::NotPresent => "not-present",
+            OperStatus::LowerLayerDown => "lower-layer-down",
+        }
+    }
+}
+
+impl fmt::Display for OperStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A network adapter and the addresses assigned to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Adapter {
+    pub name: String,
+    pub description: String,
+    pub kind: AdapterKind,
+    pub status: OperStatus,
+    pub mac: Option<String>,
+    pub addresses: Vec<IpAddr>,
+    /// Addresses that are temporary (privacy extension) IPv6 addresses.
+    /// Only known on Linux; on Windows they are classed by their prefix.
+    pub temporary: Vec<IpAddr>,
+    pub gateways: Vec<IpAddr>,
+    pub dns_servers: Vec<IpAddr>,
+}
+
+/// Formats a hardware address as colon separated hex, e.g. `00:15:5D:01:02:03`.
+pub fn format_mac(bytes: &[u8]) -> Option<String> {
+    if bytes.is_empty() || bytes.iter().all(|b| *b == 0) {
+        return None;
+    }
+    Some(
+        bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":"),
+    )
+}
+
+/// Something that can enumerate the machine's network adapters.
+pub trait AddressSource {
+    fn adapters(&self) -> std::io::Result<Vec<Adapter>>;
+}
+
+/// The address source for the platform we are running on.
+pub fn system_source() -> Box<dyn AddressSource + Send> {
+    #[cfg(windows)]
+    return Box::new(IpconfigSource);
+    #[cfg(target_os = "linux")]
+    return Box::new(IfaddrsSource);
+}
+
+/// Windows adapters from `GetAdaptersAddresses` via the `ipconfig` crate.
+#[cfg(windows)]
+#[derive(Debug, Default)]
+pub struct IpconfigSource;
+
+#[cfg(windows)]
+impl AddressSource for IpconfigSource {
+    fn adapters(&self) -> std::io::Result<Vec<Adapter>> {
+        let adapters = ipconfig::get_adapters().map_err(std::io::Error::other)?;
+
+        Ok(adapters
+            .iter()
+            .map(|adapter| Adapter {
+                name: adapter.friendly_name().to_owned(),
+                description: adapter.description().to_owned(),
+                kind: match adapter.if_type() {
+                    ipconfig::IfType::EthernetCsmacd => AdapterKind::Ethernet,
+                    ipconfig::IfType::Ieee80211 => AdapterKind::Wireless,
+                    ipconfig::IfType::SoftwareLoopback => AdapterKind::Loopback,
+                    ipconfig::IfType::Tunnel => AdapterKind::Tunnel,
+                    ipconfig::IfType::Ppp => AdapterKind::Ppp,
+                    _ => AdapterKind::Other,
+                },
+                status: match adapter.oper_status() {
+                    ipconfig::OperStatus::IfOperStatusUp => OperStatus::Up,
+                    ipconfig::OperStatus::IfOperStatusDown => OperStatus::Down,
+                    ipconfig::OperStatus::IfOperStatusTesting => OperStatus::Testing,
+                    ipconfig::OperStatus::IfOperStatusUnknown => OperStatus::Unknown,
+                    ipconfig::OperStatus::IfOperStatusDormant => OperStatus::Dormant,
+                    ipconfig::OperStatus::IfOperStatusNotPresent => OperStatus::NotPresent,
+                    ipconfig::OperStatus::IfOperStatusLowerLayerDown => OperStatus::LowerLayerDown,
+                },
+                mac: adapter.physical_address().and_then(format_mac),
+                addresses: adapter.ip_addresses().to_vec(),
+                // A random suffix origin doesn't tell temporary addresses
+                // apart: with `RandomizeIdentifiers`, the default, stable and
+                // link-local addresses have one too.
+                temporary: vec![],
+                gateways: adapter.gateways().to_vec(),
+                dns_servers: adapter.dns_servers().to_vec(),
+            })
+            .collect())
+    }
+}
+
+/// Linux interfaces from `getifaddrs`, grouped by interface name.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+pub struct IfaddrsSource;
+
+#[cfg(target_os = "linux")]
+impl AddressSource for IfaddrsSource {
+    fn adapters(&self) -> std::io::Result<Vec<Adapter>> {
+        let mut adapters: Vec<Adapter> = vec![];
+        let temporary = temporary_addresses();
+        let gateways = default_gateways();
+        let dns_servers = dns_servers();
+
+        for ifaddr in nix::ifaddrs::getifaddrs()? {
+            let index = match adapters
+                .iter()
+                .position(|a| a.name == ifaddr.interface_name)
+            {
+                Some(index) => index,
+                None => {
+                    let name = &ifaddr.interface_name;
+                    let kind = adapter_kind(name);
+                    adapters.push(Adapter {
+                        name: name.clone(),
+                        kind,
+                        status: oper_status(name),
+                        gateways: gateways
+                            .iter()
+                            .filter(|(iface, _)| iface == name)
+                            .map(|(_, gw)| *gw)
+                            .collect(),
+                        dns_servers: if kind == AdapterKind::Loopback {
+                            vec![]
+                        } else {
+                            dns_servers.clone()
+                        },
+                        ..Default::default()
+                    });
+                    adapters.len() - 1
+                }
+            };
+            let adapter = &mut adapters[index];
+
+            let Some(address) = ifaddr.address else {
+                continue;
+            };
+            if let Some(link) = address.as_link_addr() {
+                adapter.mac = link.addr().and_then(|mac| format_mac(&mac));
+            } else if let Some(sin) = address.as_sockaddr_in() {
+                adapter.addresses.push(IpAddr::V4(sin.ip()));
+            } else if let Some(sin6) = address.as_sockaddr_in6() {
+                let ip = IpAddr::V6(sin6.ip());
+                if temporary.contains(&ip) {
+                    adapter.temporary.push(ip);
+                }
+                adapter.addresses.push(ip);
+            }
+        }
+
+        Ok(adapters)
+    }
+}
+
+/// Maps the ARPHRD link type from sysfs onto an `AdapterKind`.
+#[cfg(target_os = "linux")]
+fn adapter_kind(name: &str) -> AdapterKind {
+    let sysfs = std::path::Path::new("/sys/class/net").join(name);
+    let link_type = std::fs::read_to_string(sysfs.join("type"))
+        .ok()
+        .and_then(|t| t.trim().parse::<u32>().ok());
+
+    match link_type {
+        Some(1) if sysfs.join("wireless").exists() => AdapterKind::Wireless,
+        Some(1) => AdapterKind::Ethernet,
+        Some(512) => AdapterKind::Ppp,
+        Some(772) => AdapterKind::Loopback,
+        Some(768 | 769 | 776 | 778 | 823 | 65534) => AdapterKind::Tunnel,
+        _ => AdapterKind::Other,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn oper_status(name: &str) -> OperStatus {
+    let path = std::path::Path::new("/sys/class/net")
+        .join(name)
+        .join("operstate");
+    match std::fs::read_to_string(path).as_deref().map(str::trim) {
+        Ok("up") => OperStatus::Up,
+        Ok("down") => OperStatus::Down,
+        Ok("testing") => OperStatus::Testing,
+        Ok("dormant") => OperStatus::Dormant,
+        Ok("notpresent") => OperStatus::NotPresent,
+        Ok("lowerlayerdown") => OperStatus::LowerLayerDown,
+        _ => OperStatus::Unknown,
+    }
+}
+
+/// `(interface, gateway)` pairs of the default routes in the main table.
+#[cfg(target_os = "linux")]
+fn default_gateways() -> Vec<(String, IpAddr)> {
+    let mut gateways = vec![];
+    if let Ok(content) = std::fs::read_to_string("/proc/net/route") {
+        gateways.extend(content.lines().skip(1).filter_map(ipv4_default_route));
+    }
+    if let Ok(content) = std::fs::read_to_string("/proc/net/ipv6_route") {
+        gateways.extend(content.lines().filter_map(ipv6_default_route));
+    }
+    gateways
+}
+
+/// A `/proc/net/route` line if it is a default route with a gateway.
+/// Iface Destination Gateway ..., addresses as hex of the in-memory
+/// (network order) bytes read as a native endian u32.
+#[cfg(target_os = "linux")]
+fn ipv4_default_route(line: &str) -> Option<(String, IpAddr)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (Some(iface), Some(&"00000000"), Some(gw)) = (fields.first(), fields.get(1), fields.get(2))
+    else {
+        return None;
+    };
+    let gw = u32::from_str_radix(gw, 16).ok().filter(|gw| *gw != 0)?;
+    let gw = std::net::Ipv4Addr::from(gw.to_ne_bytes());
+    Some((iface.to_string(), IpAddr::V4(gw)))
+}
+
+/// A `/proc/net/ipv6_route` line if it is a default route with a gateway.
+/// dest dest_len src src_len next_hop metric refcnt use flags iface
+#[cfg(target_os = "linux")]
+fn ipv6_default_route(line: &str) -> Option<(String, IpAddr)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 10 || fields[1] != "00" || u128::from_str_radix(fields[0], 16) != Ok(0) {
+        return None;
+    }
+    let gw = u128::from_str_radix(fields[4], 16)
+        .ok()
+        .filter(|gw| *gw != 0)?;
+    Some((fields[9].to_string(), IpAddr::V6(Ipv6Addr::from(gw))))
+}
+
+/// Linux resolvers are system wide, so every adapter reports the same list.
+#[cfg(target_os = "linux")]
+fn dns_servers() -> Vec<IpAddr> {
+    let Ok(content) = std::fs::read_to_string("/etc/resolv.conf") else {
+        return vec![];
+    };
+    content
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|server| server.trim().parse().ok())
+        .collect()
+}
+
+/// IPv6 addresses flagged `IFA_F_TEMPORARY`. `getifaddrs` does not report
+/// address flags, so they are read from `/proc/net/if_inet6`.
+#[cfg(target_os = "linux")]
+fn temporary_addresses() -> Vec<IpAddr> {
+    const IFA_F_TEMPORARY: u32 = 0x01;
+
+    let content = match std::fs::read_to_string("/proc/net/if_inet6") {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::debug!("Failed to read /proc/net/if_inet6: {}", e);
+            return vec![];
+        }
+    };
+
+    let mut ip_addrs = vec![];
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (Some(addr), Some(flags)) = (fields.first(), fields.get(4)) else {
+            continue;
+        };
+        let (Ok(addr), Ok(flags)) = (
+            u128::from_str_radix(addr, 16),
+            u32::from_str_radix(flags, 16),
+        ) else {
+            continue;
+        };
+        if flags & IFA_F_TEMPORARY != 0 {
+            ip_addrs.push(IpAddr::V6(Ipv6Addr::from(addr)));
+        }
+    }
+    ip_addrs
+}
+
+/// A fixed, in-memory adapter list for exercising the polling logic.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct FakeSource {
+    pub adapters: Vec<Adapter>,
+}
+
+#[cfg(test)]
+impl AddressSource for FakeSource {
+    fn adapters(&self) -> std::io::Result<Vec<Adapter>> {
+        Ok(self.adapters.clone())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_routes_parse_from_proc() {
+        let gateway = |addr: &str| (String::from("eth0"), addr.parse::<IpAddr>().unwrap());
+
+        // As captured on x86_64; the kernel writes the address bytes as a
+        // native endian number.
+        let route = "eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0";
+        if cfg!(target_endian = "little") {
+            assert_eq!(ipv4_default_route(route), Some(gateway("192.168.1.1")));
+        }
+        let native = u32::from_ne_bytes([192, 168, 1, 1]);
+        let route = format!("eth0\t00000000\t{:08X}\t0003\t0\t0\t100\t00000000", native);
+        assert_eq!(ipv4_default_route(&route), Some(gateway("192.168.1.1")));
+        let subnet = "eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0";
+        assert_eq!(ipv4_default_route(subnet), None);
+
+        let route = "00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+                     fe800000000000000000000000000001 00000400 00000001 00000000 00000003 eth0";
+        assert_eq!(ipv6_default_route(route), Some(gateway("fe80::1")));
+        let link = "fe800000000000000000000000000000 40 00000000000000000000000000000000 00 \
+                    00000000000000000000000000000000 00000100 00000001 00000000 00000001 eth0";
+        assert_eq!(ipv6_default_route(link), None);
+    }
+}
@@ -0,0 +1,347 @@
+use crate::writer::{self, RetryPolicy};
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// Where settings are kept. Values are TOML values whatever the backend, so
+/// every store is read and validated by the same code in `config`. A missing
+/// value is `Ok(None)`; errors are only for a store that can't be read.
+pub trait ConfigStore {
+    /// Where the values live, for messages.
+    fn name(&self) -> String;
+    fn get(&self, key: &str) -> std::io::Result<Option<Value>>;
+    fn set(&mut self, key: &str, value: Value) -> std::io::Result<()>;
+    /// Removing a value that isn't there is not an error.
+    fn unset(&mut self, key: &str) -> std::io::Result<()>;
+    /// Every setting the store holds, sorted by key.
+    fn entries(&self) -> std::io::Result<Vec<(String, Value)>>;
+    /// The file holding the settings, for stores that are one.
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// The store the installed service reads: its registry key on Windows, the
+/// settings file `/etc/<service>.conf` on Linux.
+#[cfg(windows)]
+pub fn system_store(service_name: &str) -> Box<dyn ConfigStore> {
+    Box::new(RegistryStore::new(service_name))
+}
+
+#[cfg(target_os = "linux")]
+pub fn system_store(service_name: &str) -> Box<dyn ConfigStore> {
+    Box::new(FileStore::new(format!("/etc/{}.conf", service_name)))
+}
+
+/// Settings in memory, for tests.
+#[cfg(test)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryStore {
+    name: String,
+    values: std::collections::BTreeMap<String, Value>,
+}
+
+#[cfg(test)]
+impl MemoryStore {
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = name.to_owned();
+        self
+    }
+}
+
+#[cfg(test)]
+impl From<toml::Table> for MemoryStore {
+    fn from(table: toml::Table) -> Self {
+        Self {
+            name: "memory".to_owned(),
+            values: table.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl ConfigStore for MemoryStore {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Option<Value>> {
+        Ok(self.values.get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> std::io::Result<()> {
+        self.values.insert(key.to_owned(), value);
+        Ok(())
+    }
+
+    fn unset(&mut self, key: &str) -> std::io::Result<()> {
+        self.values.remove(key);
+        Ok(())
+    }
+
+    fn entries(&self) -> std::io::Result<Vec<(String, Value)>> {
+        Ok(self
+            .values
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+}
+
+/// A TOML file of `key = value` settings. A missing file has no values.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn read(&self) -> std::io::Result<toml::Table> {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(toml::Table::new()),
+            Err(e) => {
+                tracing::error!("Failed to read {}: {}", self.path.display(), e);
+                return Err(e);
+            }
+        };
+        content.parse().map_err(|e: toml::de::Error| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+        })
+    }
+
+    /// Replaced atomically, since the running service watches the file and
+    /// reloads it as soon as it changes.
+    fn write(&self, table: &toml::Table) -> std::io::Result<()> {
+        let content = toml::to_string(table).map_err(std::io::Error::other)?;
+        // Logged by `write_atomic`.
+        writer::write_atomic(&self.path, content.as_bytes(), RetryPolicy::default())
+    }
+}
+
+impl ConfigStore for FileStore {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Option<Value>> {
+        Ok(self.read()?.remove(key))
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> std::io::Result<()> {
+        let mut table = self.read()?;
+        table.insert(key.to_owned(), value);
+        self.write(&table)
+    }
+
+    fn unset(&mut self, key: &str) -> std::io::Result<()> {
+        let mut table = self.read()?;
+        if table.remove(key).is_some() {
+            self.write(&table)?;
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> std::io::Result<Vec<(String, Value)>> {
+        Ok(self.read()?.into_iter().collect())
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+/// The service's key under `HKLM\SYSTEM\CurrentControlSet\Services`. Strings
+/// are `REG_SZ`, numbers `REG_QWORD`, booleans `REG_DWORD` 0/1 and lists
+/// `REG_MULTI_SZ`; `REG_DWORD` numbers written by older versions still read.
+#[cfg(windows)]
+pub struct RegistryStore {
+    regpath: String,
+}
+
+/// Path of the service's key under `HKEY_LOCAL_MACHINE`.
+#[cfg(windows)]
+pub fn service_key(service_name: &str) -> String {
+    format!("SYSTEM\\CurrentControlSet\\Services\\{}", service_name)
+}
+
+/// `HRESULT_FROM_WIN32(ERROR_FILE_NOT_FOUND)`, for a missing key or value.
+#[cfg(windows)]
+const NOT_FOUND: i32 = 0x8007_0002_u32 as i32;
+
+#[cfg(windows)]
+impl RegistryStore {
+    pub fn new(service_name: &str) -> Self {
+        Self {
+            regpath: service_key(service_name),
+        }
+    }
+
+    /// The service key, or `None` before the service is installed.
+    fn open(&self) -> std::io::Result<Option<windows_registry::Key>> {
+        match windows_registry::LOCAL_MACHINE
+            .options()
+            .read()
+            .write()
+            .open(&self.regpath)
+        {
+            Ok(k) => Ok(Some(k)),
+            Err(e) if e.code().0 == NOT_FOUND => Ok(None),
+            Err(e) => {
+                tracing::error!("Failed to open {}: {}", self.regpath, e.message());
+                Err(std::io::Error::from_raw_os_error(e.code().0))
+            }
+        }
+    }
+
+    fn open_existing(&self) -> std::io::Result<windows_registry::Key> {
+        self.open()?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} does not exist, install the service first", self.regpath),
+            )
+        })
+    }
+
+    fn read(key: &windows_registry::Key, name: &str) -> windows_registry::Result<Value> {
+        use windows_registry::Type;
+
+        Ok(match key.get_type(name)? {
+            // Numbers beyond a TOML integer are passed on as text, for the
+            // setting to reject with its name.
+            Type::U32 | Type::U64 => {
+                let n = key.get_u64(name)?;
+                i64::try_from(n).map_or_else(|_| Value::String(n.to_string()), Value::Integer)
+            }
+            Type::MultiString => Value::Array(
+                key.get_multi_string(name)?
+                    .into_iter()
+                    .map(Value::String)
+                    .collect(),
+            ),
+            _ => Value::String(key.get_string(name)?),
+        })
+    }
+}
+
+#[cfg(windows)]
+impl ConfigStore for RegistryStore {
+    fn name(&self) -> String {
+        format!("HKLM\\{}", self.regpath)
+    }
+
+    fn get(&self, name: &str) -> std::io::Result<Option<Value>> {
+        let Some(key) = self.open()? else {
+            return Ok(None);
+        };
+        match Self::read(&key, name) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.code().0 == NOT_FOUND => Ok(None),
+            Err(e) => {
+                tracing::error!("Failed to read {}: {}", name, e.message());
+                Err(std::io::Error::from_raw_os_error(e.code().0))
+            }
+        }
+    }
+
+    fn set(&mut self, name: &str, value: Value) -> std::io::Result<()> {
+        let key = self.open_existing()?;
+        let result = match &value {
+            Value::String(s) => key.set_string(name, s),
+            Value::Integer(i) => match u64::try_from(*i) {
+                Ok(i) => key.set_u64(name, i),
+                Err(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("{}: negative numbers can't be stored", name),
+                    ))
+                }
+            },
+            Value::Boolean(b) => key.set_u32(name, u32::from(*b)),
+            Value::Array(items) if items.iter().all(Value::is_str) => {
+                let items: Vec<&str> = items.iter().filter_map(Value::as_str).collect();
+                key.set_multi_string(name, &items)
+            }
+            // Lists of tables, like `outputs`, are kept as TOML text.
+            Value::Array(_) => key.set_string(name, value.to_string()),
+            other => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{}: {} values can't be stored", name, other.type_str()),
+                ))
+            }
+        };
+        result.map_err(|e| {
+            tracing::error!("Failed to set {}: {}", name, e.message());
+            std::io::Error::from_raw_os_error(e.code().0)
+        })
+    }
+
+    fn unset(&mut self, name: &str) -> std::io::Result<()> {
+        let Some(key) = self.open()? else {
+            return Ok(());
+        };
+        match key.remove_value(name) {
+            Ok(()) => Ok(()),
+            Err(e) if e.code().0 == NOT_FOUND => Ok(()),
+            Err(e) => {
+                tracing::error!("Failed to remove {}: {}", name, e.message());
+                Err(std::io::Error::from_raw_os_error(e.code().0))
+            }
+        }
+    }
+
+    /// Only the service's own settings; the key also holds the values the
+    /// service control manager keeps there (`ImagePath`, `Start`, ...).
+    fn entries(&self) -> std::io::Result<Vec<(String, Value)>> {
+        let mut entries = vec![];
+        let names = crate::config::KEYS
+            .iter()
+            .chain([&crate::config::CONFIG.name]);
+        for name in names {
+            if let Some(value) = self.get(name)? {
+                entries.push((name.to_string(), value));
+            }
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("ip_to_file_store_{}.conf", std::process::id()));
+        let mut store = FileStore::new(&path);
+        assert_eq!(store.get("time_delay").unwrap(), None);
+
+        store.set("time_delay", Value::Integer(60)).unwrap();
+        store
+            .set(
+                "filters",
+                Value::Array(vec![Value::from("deny type:tunnel")]),
+            )
+            .unwrap();
+        assert_eq!(store.get("time_delay").unwrap(), Some(Value::Integer(60)));
+        assert_eq!(
+            store
+                .entries()
+                .unwrap()
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>(),
+            vec!["filters", "time_delay"]
+        );
+
+        store.unset("time_delay").unwrap();
+        store.unset("time_delay").unwrap();
+        assert_eq!(store.get("time_delay").unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,610 @@
+use crate::config::Config;
+use std::{
+    path::PathBuf,
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+/// Messages that wake the service loop before the next poll is due.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Shutdown,
+    /// The machine is shutting down: write a final snapshot, then stop.
+    SystemShutdown,
+    AddressChange,
+    /// The settings may have changed and should be loaded again.
+    Reload,
+    /// Suspend polling until `Continue`.
+    Pause,
+    Continue,
+    /// Poll now and rewrite every output.
+    Refresh,
+    /// Reopen the log file and rotate the journal.
+    RotateLogs,
+    /// Log the health counters and the settings in effect.
+    Diagnostics,
+}
+
+/// What the service loop should do after `wait` returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wake {
+    Poll,
+    /// Poll and write every output even if nothing changed.
+    Refresh,
+    Shutdown,
+    SystemShutdown,
+    Pause,
+}
+
+/// Blocks until the poll interval elapses or an event arrives. An address
+/// change or reload is followed by a `debounce` window in which further events
+/// are absorbed, so a burst of notifications (DHCP renew, link flap, an editor
+/// saving a file in several steps) costs one poll. `RotateLogs` and
+/// `Diagnostics` are handed to `on_demand` without ending the wait. An
+/// interval too long for the clock waits for an event only.
+pub fn wait(
+    rx: &Receiver<Event>,
+    poll_rate: Duration,
+    debounce: Duration,
+    on_demand: &mut dyn FnMut(Event),
+) -> Wake {
+    let mut deadline = Instant::now().checked_add(poll_rate);
+    let mut debouncing = false;
+    loop {
+        let event = match deadline {
+            Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match event {
+            Ok(Event::Shutdown) | Err(RecvTimeoutError::Disconnected) => return Wake::Shutdown,
+            Ok(Event::SystemShutdown) => return Wake::SystemShutdown,
+            Ok(Event::Pause) => return Wake::Pause,
+            Ok(Event::Refresh) => return Wake::Refresh,
+            Err(RecvTimeoutError::Timeout) => return Wake::Poll,
+            Ok(event @ (Event::RotateLogs | Event::Diagnostics)) => on_demand(event),
+            Ok(Event::Continue) if !debouncing => return Wake::Poll,
+            Ok(event @ (Event::AddressChange | Event::Reload)) if !debouncing => {
+                if event == Event::AddressChange {
+                    tracing::debug!("Address change notification");
+                } else {
+                    tracing::debug!("Settings change notification");
+                }
+                debouncing = true;
+                deadline = Instant::now().checked_add(debounce);
+            }
+            Ok(Event::AddressChange | Event::Reload | Event::Continue) => (),
+        }
+    }
+}
+
+/// Blocks while the service is paused. Returns `Wake::Poll` once it is
+/// continued; notifications that arrive meanwhile are dropped, the poll after
+/// continuing picks their changes up.
+pub fn paused(rx: &Receiver<Event>, on_demand: &mut dyn FnMut(Event)) -> Wake {
+    loop {
+        match rx.recv() {
+            Ok(Event::Continue) => return Wake::Poll,
+            Ok(Event::Shutdown) | Err(_) => return Wake::Shutdown,
+            Ok(Event::SystemShutdown) => return Wake::SystemShutdown,
+            Ok(event @ (Event::RotateLogs | Event::Diagnostics)) => on_demand(event),
+            Ok(Event::AddressChange | Event::Reload | Event::Pause | Event::Refresh) => (),
+        }
+    }
+}
+
+/// The notification sources of the service loop, kept in line with the
+/// settings as they are reloaded.
+pub struct Watchers {
+    tx: Sender<Event>,
+    address: Option<AddressWatcher>,
+    files: Option<FileWatcher>,
+}
+
+impl Watchers {
+    pub fn new(tx: Sender<Event>) -> Self {
+        Self {
+            tx,
+            address: None,
+            files: None,
+        }
+    }
+
+    /// Starts or stops address notifications as `watch` says and follows
+    /// the settings files to their current paths.
+    pub fn update(&mut self, config: &Config) {
+        if config.watch && self.address.is_none() {
+            match AddressWatcher::start(self.tx.clone()) {
+                Ok(watcher) => self.address = Some(watcher),
+                Err(e) => tracing::error!("Failed to watch address changes, polling only: {}", e),
+            }
+        } else if !config.watch && self.address.is_some() {
+            tracing::info!("Stopped watching address changes");
+            self.address = None;
+        }
+
+        if self.files.as_ref().map(|w| w.paths.as_slice()) != Some(config.files.as_slice()) {
+            self.files = None;
+            if config.files.is_empty() {
+                return;
+            }
+            match FileWatcher::start(&config.files, self.tx.clone()) {
+                Ok(watcher) => self.files = Some(watcher),
+                Err(e) => tracing::error!("Failed to watch settings files: {}", e),
+            }
+        }
+    }
+}
+
+/// Watches settings files and sends `Event::Reload` when one is written,
+/// replaced or removed. The directories are watched rather than the files,
+/// since editors often save by replacing the file.
+pub struct FileWatcher {
+    _watcher: notify::RecommendedWatcher,
+    paths: Vec<PathBuf>,
+}
+
+impl FileWatcher {
+    pub fn start(paths: &[PathBuf], tx: Sender<Event>) -> notify::Result<Self> {
+        use notify::Watcher;
+
+        let files = paths
+            .iter()
+            .map(std::path::absolute)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let mut dirs: Vec<PathBuf> = files
+            .iter()
+            .filter_map(|f| f.parent().map(PathBuf::from))
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                // Reading the file is an access event too; ignore those or
+                // every reload would trigger the next.
+                Ok(event) if event.kind.is_access() => (),
+                Ok(event) => {
+                    if event.paths.iter().any(|p| files.contains(p)) {
+                        let _ = tx.send(Event::Reload);
+                    }
+                }
+                Err(e) => tracing::error!("Settings file notification failed: {}", e),
+            })?;
+        for dir in &dirs {
+            watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+            tracing::debug!("Watching {} for settings changes", dir.display());
+        }
+
+        Ok(Self {
+            _watcher: watcher,
+            paths: paths.to_vec(),
+        })
+    }
+}
+
+/// Listener thread for changes to the service's registry key. Dropping it
+/// signals the thread and waits for it to exit.
+#[cfg(windows)]
+pub struct RegistryWatcher {
+    stop: windows_sys::Win32::Foundation::HANDLE,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+#[cfg(windows)]
+impl RegistryWatcher {
+    /// Waits on `RegNotifyChangeKeyValue` for values set under `regpath`, or
+    /// the stop event.
+    pub fn start(regpath: String, tx: Sender<Event>) -> Self {
+        use windows_sys::Win32::{
+            Foundation::{CloseHandle, ERROR_SUCCESS, WAIT_OBJECT_0},
+            System::{
+                Registry::{RegNotifyChangeKeyValue, REG_NOTIFY_CHANGE_LAST_SET},
+                Threading::{CreateEventW, WaitForMultipleObjects, INFINITE},
+            },
+        };
+
+        // SAFETY: no security attributes or name; a manual reset event that
+        // stays set once `Drop` sets it.
+        let stop = unsafe {
+            CreateEventW(
+                std::ptr::null(),
+                true.into(),
+                false.into(),
+                std::ptr::null(),
+            )
+        };
+        if stop.is_null() {
+            tracing::error!(
+                "Failed to watch {}: {}",
+                regpath,
+                std::io::Error::last_os_error()
+            );
+            return RegistryWatcher { stop, thread: None };
+        }
+
+        // Handles are plain pointers; `Drop` joins the thread before closing it.
+        let stop_handle = stop as usize;
+        let thread = std::thread::spawn(move || {
+            let stop = stop_handle as windows_sys::Win32::Foundation::HANDLE;
+            let key = match windows_registry::LOCAL_MACHINE
+                .options()
+                .read()
+                .open(&regpath)
+            {
+                Ok(key) => key,
+                Err(e) => {
+                    tracing::error!("Failed to watch {}: {}", regpath, e.message());
+                    return;
+                }
+            };
+            // SAFETY: as above, an auto reset event for the notifications.
+            let changed = unsafe {
+                CreateEventW(
+                    std::ptr::null(),
+                    false.into(),
+                    false.into(),
+                    std::ptr::null(),
+                )
+            };
+            if changed.is_null() {
+                tracing::error!(
+                    "Failed to watch {}: {}",
+                    regpath,
+                    std::io::Error::last_os_error()
+                );
+                return;
+            }
+            loop {
+                // SAFETY: `key` and `changed` stay open until after the wait;
+                // the call returns at once and sets `changed` on a change.
+                let ret = unsafe {
+                    RegNotifyChangeKeyValue(
+                        key.as_raw(),
+                        false.into(),
+                        REG_NOTIFY_CHANGE_LAST_SET,
+                        changed,
+                        true.into(),
+                    )
+                };
+                if ret != ERROR_SUCCESS {
+                    tracing::error!(
+                        "Failed to watch {}: {}",
+                        regpath,
+                        std::io::Error::from_raw_os_error(ret as i32)
+                    );
+                    break;
+                }
+                let handles = [stop, changed];
+                // SAFETY: both handles are valid events.
+                let wake =
+                    unsafe { WaitForMultipleObjects(2, handles.as_ptr(), false.into(), INFINITE) };
+                if wake != WAIT_OBJECT_0 + 1 || tx.send(Event::Reload).is_err() {
+                    break;
+                }
+            }
+            // SAFETY: created above and no longer waited on.
+            unsafe { CloseHandle(changed) };
+        });
+
+        RegistryWatcher {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for RegistryWatcher {
+    fn drop(&mut self) {
+        use windows_sys::Win32::{Foundation::CloseHandle, System::Threading::SetEvent};
+
+        if self.stop.is_null() {
+            return;
+        }
+        // SAFETY: `stop` is the event created in `start`, closed only here
+        // after the thread waiting on it has exited.
+        unsafe {
+            if SetEvent(self.stop) != 0 {
+                if let Some(thread) = self.thread.take() {
+                    let _ = thread.join();
+                }
+                CloseHandle(self.stop);
+            }
+        }
+    }
+}
+
+/// Registration for OS address change notifications; dropping it unregisters.
+#[cfg(windows)]
+pub struct AddressWatcher {
+    handles: Vec<windows_sys::Win32::Foundation::HANDLE>,
+    tx: *mut Sender<Event>,
+}
+
+#[cfg(windows)]
+impl AddressWatcher {
+    /// Subscribes to unicast address and IP interface changes with
+    /// `NotifyUnicastIpAddressChange` and `NotifyIpInterfaceChange`.
+    pub fn start(tx: Sender<Event>) -> std::io::Result<Self> {
+        use windows_sys::Win32::{
+            Foundation::NO_ERROR,
+            NetworkManagement::IpHelper::{NotifyIpInterfaceChange, NotifyUnicastIpAddressChange},
+            Networking::WinSock::AF_UNSPEC,
+        };
+
+        let mut watcher = AddressWatcher {
+            handles: vec![],
+            tx: Box::into_raw(Box::new(tx)),
+        };
+
+        let mut handle = std::ptr::null_mut();
+        // SAFETY: `watcher.tx` stays valid until `Drop`, which cancels the
+        // registration (waiting for running callbacks) before freeing it.
+        let ret = unsafe {
+            NotifyUnicastIpAddressChange(
+                AF_UNSPEC,
+                Some(unicast_address_changed),
+                watcher.tx as *const _,
+                false,
+                &mut handle,
+            )
+        };
+        if ret != NO_ERROR {
+            return Err(std::io::Error::from_raw_os_error(ret as i32));
+        }
+        watcher.handles.push(handle);
+
+        let mut handle = std::ptr::null_mut();
+        // SAFETY: as above.
+        let ret = unsafe {
+            NotifyIpInterfaceChange(
+                AF_UNSPEC,
+                Some(interface_changed),
+                watcher.tx as *const _,
+                false,
+                &mut handle,
+            )
+        };
+        if ret != NO_ERROR {
+            return Err(std::io::Error::from_raw_os_error(ret as i32));
+        }
+        watcher.handles.push(handle);
+
+        Ok(watcher)
+    }
+}
+
+#[cfg(windows)]
+impl Drop for AddressWatcher {
+    fn drop(&mut self) {
+        use windows_sys::Win32::NetworkManagement::IpHelper::CancelMibChangeNotify2;
+
+        for handle in self.handles.drain(..) {
+            // SAFETY: `handle` came from a successful Notify* call and blocks
+            // until in-flight callbacks return.
+            unsafe { CancelMibChangeNotify2(handle) };
+        }
+        // SAFETY: no callback can reference `tx` any more.
+        drop(unsafe { Box::from_raw(self.tx) });
+    }
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn unicast_address_changed(
+    context: *const std::ffi::c_void,
+    _row: *const windows_sys::Win32::NetworkManagement::IpHelper::MIB_UNICASTIPADDRESS_ROW,
+    _notification_type: windows_sys::Win32::NetworkManagement::IpHelper::MIB_NOTIFICATION_TYPE,
+) {
+    notify(context);
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn interface_changed(
+    context: *const std::ffi::c_void,
+    _row: *const windows_sys::Win32::NetworkManagement::IpHelper::MIB_IPINTERFACE_ROW,
+    _notification_type: windows_sys::Win32::NetworkManagement::IpHelper::MIB_NOTIFICATION_TYPE,
+) {
+    notify(context);
+}
+
+#[cfg(windows)]
+fn notify(context: *const std::ffi::c_void) {
+    // SAFETY: `context` is the `Sender` owned by the live `AddressWatcher`.
+    let tx = unsafe { &*(context as *const Sender<Event>) };
+    // The loop may already be gone during shutdown.
+    let _ = tx.send(Event::AddressChange);
+}
+
+/// Listener thread for rtnetlink address notifications; dropping it wakes
+/// the thread and waits for it to exit.
+#[cfg(target_os = "linux")]
+pub struct AddressWatcher {
+    stop: std::sync::Arc<nix::sys::eventfd::EventFd>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+#[cfg(target_os = "linux")]
+impl AddressWatcher {
+    /// Joins the `RTMGRP_IPV4_IFADDR`/`RTMGRP_IPV6_IFADDR` groups, which carry
+    /// `RTM_NEWADDR`/`RTM_DELADDR`, plus `RTMGRP_LINK` for carrier changes.
+    /// The thread waits on the socket and an eventfd, so it exits as soon as
+    /// the watcher is dropped, or once the loop stops listening.
+    pub fn start(tx: Sender<Event>) -> std::io::Result<Self> {
+        use nix::{
+            libc,
+            poll::{poll, PollFd, PollFlags, PollTimeout},
+            sys::{
+                eventfd::{EfdFlags, EventFd},
+                socket::{
+                    bind, recv, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag,
+                    SockProtocol, SockType,
+                },
+            },
+        };
+        use std::os::fd::{AsFd, AsRawFd};
+
+        let fd = socket(
+            AddressFamily::Netlink,
+            SockType::Raw,
+            SockFlag::SOCK_CLOEXEC,
+            SockProtocol::NetlinkRoute,
+        )?;
+        let groups =
+            (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+        bind(fd.as_raw_fd(), &NetlinkAddr::new(0, groups))?;
+
+        let stop = std::sync::Arc::new(EventFd::from_flags(EfdFlags::EFD_CLOEXEC)?);
+        let stopped = stop.clone();
+        let thread = std::thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            loop {
+                let mut fds = [
+                    PollFd::new(fd.as_fd(), PollFlags::POLLIN),
+                    PollFd::new(stopped.as_fd(), PollFlags::POLLIN),
+                ];
+                match poll(&mut fds, PollTimeout::NONE) {
+                    Ok(_) => (),
+                    Err(nix::errno::Errno::EINTR) => continue,
+                    Err(e) => {
+                        tracing::error!("Netlink poll failed: {}", e);
+                        break;
+                    }
+                }
+                let ready = |fd: &PollFd| fd.revents().is_some_and(|r| !r.is_empty());
+                if ready(&fds[1]) {
+                    break;
+                }
+                if !ready(&fds[0]) {
+                    continue;
+                }
+                match recv(fd.as_raw_fd(), &mut buf, MsgFlags::empty()) {
+                    Ok(_) => {
+                        if tx.send(Event::AddressChange).is_err() {
+                            break;
+                        }
+                    }
+                    Err(nix::errno::Errno::EINTR) => (),
+                    Err(e) => {
+                        tracing::error!("Netlink receive failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(AddressWatcher {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for AddressWatcher {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop.write(1) {
+            tracing::error!("Failed to stop the netlink listener: {}", e);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn wait_times_out_to_poll() {
+        let (_tx, rx) = channel();
+        let wake = wait(&rx, Duration::from_millis(10), Duration::ZERO, &mut |_| ());
+        assert_eq!(wake, Wake::Poll);
+    }
+
+    #[test]
+    fn wait_debounces_address_changes() {
+        let (tx, rx) = channel();
+        for _ in 0..5 {
+            tx.send(Event::AddressChange).unwrap();
+        }
+        let wake = wait(
+            &rx,
+            Duration::from_secs(60),
+            Duration::from_millis(20),
+            &mut |_| (),
+        );
+        assert_eq!(wake, Wake::Poll);
+        assert!(rx.try_recv().is_err());
+
+        // An interval past the end of the clock still ends with the debounce.
+        tx.send(Event::AddressChange).unwrap();
+        let wake = wait(&rx, Duration::MAX, Duration::from_millis(20), &mut |_| ());
+        assert_eq!(wake, Wake::Poll);
+    }
+
+    #[test]
+    fn shutdown_interrupts_debounce() {
+        let (tx, rx) = channel();
+        tx.send(Event::AddressChange).unwrap();
+        tx.send(Event::Shutdown).unwrap();
+        let wake = wait(
+            &rx,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            &mut |_| (),
+        );
+        assert_eq!(wake, Wake::Shutdown);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dropping_the_address_watcher_ends_its_thread() {
+        let (tx, rx) = channel();
+        drop(AddressWatcher::start(tx).unwrap());
+        // The thread held the only sender.
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn paused_waits_for_continue() {
+        let (tx, rx) = channel();
+        tx.send(Event::Pause).unwrap();
+        assert_eq!(
+            wait(&rx, Duration::from_secs(60), Duration::ZERO, &mut |_| ()),
+            Wake::Pause
+        );
+        tx.send(Event::AddressChange).unwrap();
+        tx.send(Event::Continue).unwrap();
+        assert_eq!(paused(&rx, &mut |_| ()), Wake::Poll);
+        tx.send(Event::SystemShutdown).unwrap();
+        assert_eq!(paused(&rx, &mut |_| ()), Wake::SystemShutdown);
+    }
+
+    #[test]
+    fn on_demand_events_keep_the_debounce() {
+        let (tx, rx) = channel();
+        tx.send(Event::AddressChange).unwrap();
+        tx.send(Event::Diagnostics).unwrap();
+        let mut handled = vec![];
+        let wake = wait(
+            &rx,
+            Duration::from_secs(60),
+            Duration::from_millis(20),
+            &mut |event| handled.push(event),
+        );
+        assert_eq!(wake, Wake::Poll);
+        assert_eq!(handled, vec![Event::Diagnostics]);
+
+        tx.send(Event::Refresh).unwrap();
+        assert_eq!(
+            wait(&rx, Duration::from_secs(60), Duration::ZERO, &mut |_| ()),
+            Wake::Refresh
+        );
+    }
+}
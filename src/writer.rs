@@ -0,0 +1,130 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// How often to retry replacing an output file that a reader has locked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            delay: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Replaces `path` with `content` so readers see either the old or the new
+/// file, never a truncated one: the content goes to a temp file in the same
+/// directory, is flushed to disk and then renamed over the target.
+pub fn write_atomic(path: &Path, content: &[u8], policy: RetryPolicy) -> std::io::Result<()> {
+    let tmp_path = temp_path(path)?;
+
+    if let Err(e) = write_temp(&tmp_path, content) {
+        tracing::error!("Failed to write temp file {}: {}", tmp_path.display(), e);
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    let mut attempt = 0;
+    loop {
+        match std::fs::rename(&tmp_path, path) {
+            Ok(()) => break,
+            Err(e) if attempt < policy.retries => {
+                attempt += 1;
+                tracing::warn!(
+                    "Failed to replace {} (attempt {} of {}): {}",
+                    path.display(),
+                    attempt,
+                    policy.retries + 1,
+                    e
+                );
+                std::thread::sleep(policy.delay);
+            }
+            Err(e) => {
+                tracing::error!("Failed to replace {}: {}", path.display(), e);
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        }
+    }
+
+    sync_dir(path);
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> std::io::Result<PathBuf> {
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid output file name: {}", path.display()),
+        )
+    })?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    Ok(path.with_file_name(tmp_name))
+}
+
+fn write_temp(tmp_path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut file = File::create(tmp_path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+/// Makes the rename itself durable. Directories can't be opened as files on
+/// Windows, where the rename is already journaled by NTFS.
+#[cfg(unix)]
+fn sync_dir(path: &Path) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Err(e) = File::open(dir).and_then(|d| d.sync_all()) {
+        tracing::debug!("Failed to sync directory {}: {}", dir.display(), e);
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_file_and_cleans_up() {
+        let dir = std::env::temp_dir().join(format!("ip_to_file_writer_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ip.txt");
+
+        write_atomic(&path, b"old", RetryPolicy::default()).unwrap();
+        write_atomic(&path, b"new", RetryPolicy::default()).unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let dir = std::env::temp_dir().join(format!("ip_to_file_retry_{}", std::process::id()));
+        // Renaming a file over a non-empty directory fails on every platform.
+        std::fs::create_dir_all(dir.join("ip.txt").join("busy")).unwrap();
+
+        let policy = RetryPolicy {
+            retries: 2,
+            delay: Duration::ZERO,
+        };
+        assert!(write_atomic(&dir.join("ip.txt"), b"new", policy).is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}